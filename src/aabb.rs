@@ -27,7 +27,25 @@ impl AABB {
         let p_max = Vec3d::comp_max(b1.max, b2.max);
         AABB::new(p_min, p_max)
     }
+
+    pub fn min(&self) -> Vec3d {
+        self.min
+    }
+
+    pub fn max(&self) -> Vec3d {
+        self.max
+    }
+
+    pub fn centroid(&self) -> Vec3d {
+        0.5*(self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        2.*(d.x*d.y + d.y*d.z + d.z*d.x)
+    }
     
+    #[allow(clippy::needless_return, clippy::if_same_then_else, clippy::needless_bool)]
     pub fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> bool {
         let t1 = (self.min.x - r.ori.x)/r.dir.x;
         let t2 = (self.max.x - r.ori.x)/r.dir.x;
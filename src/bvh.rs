@@ -0,0 +1,379 @@
+use crate::aabb::AABB;
+use crate::ray::Ray;
+use crate::shape::{Hit, Objects, Shape};
+use std::sync::Arc;
+
+// Relative costs used by the surface area heuristic.
+const TRAVERSAL_COST: f64 = 0.125;
+const INTERSECT_COST: f64 = 1.;
+
+pub enum BvhContent {
+    Leaf(Vec<Arc<dyn Shape>>),
+    Branch(Box<BvhNode>, Box<BvhNode>),
+}
+
+pub struct BvhNode {
+    pub bbox: AABB,
+    pub content: BvhContent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SplitMethod {
+    Median,
+    Sah,
+}
+
+#[derive(Clone, Debug)]
+pub struct BvhBuilder {
+    pub method: SplitMethod,
+    pub max_leaf_size: usize,
+    pub bins: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct BvhStats {
+    pub depth: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    pub primitives: usize,
+    pub sah_cost: f64,
+}
+
+type Item = (Arc<dyn Shape>, AABB);
+
+impl BvhBuilder {
+    pub fn new(method: SplitMethod, max_leaf_size: usize, bins: usize) -> BvhBuilder {
+        BvhBuilder{method, max_leaf_size:max_leaf_size.max(1), bins:bins.max(2)}
+    }
+    pub fn median() -> BvhBuilder {
+        BvhBuilder::new(SplitMethod::Median, 1, 2)
+    }
+    pub fn sah() -> BvhBuilder {
+        BvhBuilder::new(SplitMethod::Sah, 4, 16)
+    }
+    pub fn build(&self, shapes: Vec<Arc<dyn Shape>>, t0: f64, t1: f64) -> BvhNode {
+        if shapes.is_empty() {
+            return BvhNode{bbox:AABB::zero(), content:BvhContent::Leaf(shapes)};
+        }
+        // The bounds are computed once here, the split only looks at them afterwards.
+        let items = shapes.into_iter().map(|s| {
+            let b = s.bound(t0, t1);
+            (s, b)
+        }).collect();
+        self.build_node(items)
+    }
+    fn build_node(&self, items: Vec<Item>) -> BvhNode {
+        let bbox = enclose(&items);
+        if items.len() == 1 {
+            return BvhNode::leaf(bbox, items);
+        }
+        let mut centroids = AABB::new(items[0].1.centroid(), items[0].1.centroid());
+        for (_, b) in &items {
+            centroids = AABB::union_box(&centroids, &AABB::new(b.centroid(), b.centroid()));
+        }
+        if (centroids.max() - centroids.min()).max_comp() <= 0. {
+            // All centroids coincide, there is nothing to split on.
+            return BvhNode::leaf(bbox, items);
+        }
+        let split = match self.method {
+            SplitMethod::Median => self.split_median(items, &centroids),
+            SplitMethod::Sah => self.split_sah(items, &bbox, &centroids),
+        };
+        match split {
+            Ok((left, right)) => {
+                let left = Box::new(self.build_node(left));
+                let right = Box::new(self.build_node(right));
+                BvhNode{bbox, content:BvhContent::Branch(left, right)}
+            }
+            Err(items) => BvhNode::leaf(bbox, items),
+        }
+    }
+    fn split_median(&self, mut items: Vec<Item>, centroids: &AABB) -> Result<(Vec<Item>, Vec<Item>), Vec<Item>> {
+        if items.len() <= self.max_leaf_size {
+            return Err(items);
+        }
+        let axis = (centroids.max() - centroids.min()).max_dim();
+        items.sort_by(|a, b| a.1.centroid()[axis].total_cmp(&b.1.centroid()[axis]));
+        let right = items.split_off(items.len()/2);
+        Ok((items, right))
+    }
+    fn split_sah(&self, items: Vec<Item>, bbox: &AABB, centroids: &AABB) -> Result<(Vec<Item>, Vec<Item>), Vec<Item>> {
+        let nbins = self.bins;
+        // Flat nodes (a planar mesh, say) have no area; any positive constant keeps the costs finite.
+        let area = if bbox.surface_area() > 0. {bbox.surface_area()} else {1.};
+        let extent = centroids.max() - centroids.min();
+        let bin_of = |b: &AABB, axis: usize| -> usize {
+            let f = (b.centroid()[axis] - centroids.min()[axis])/extent[axis];
+            usize::min((f*nbins as f64) as usize, nbins - 1)
+        };
+        // (cost, axis, index of the first bin on the right side)
+        let mut best: Option<(f64, usize, usize)> = None;
+        for axis in 0..3 {
+            if extent[axis] <= 0. {
+                continue;
+            }
+            let mut counts = vec![0usize; nbins];
+            let mut bounds: Vec<Option<AABB>> = vec![None; nbins];
+            for (_, b) in &items {
+                let i = bin_of(b, axis);
+                counts[i] += 1;
+                bounds[i] = Some(union_opt(&bounds[i], b));
+            }
+            // Sweep from the right first so the left sweep can finish the cost in one pass.
+            let mut right_area = vec![0.; nbins];
+            let mut right_count = vec![0usize; nbins];
+            let mut acc: Option<AABB> = None;
+            let mut n = 0;
+            for i in (1..nbins).rev() {
+                if let Some(b) = &bounds[i] {
+                    acc = Some(union_opt(&acc, b));
+                }
+                n += counts[i];
+                right_area[i] = acc.as_ref().map_or(0., |b| b.surface_area());
+                right_count[i] = n;
+            }
+            let mut acc: Option<AABB> = None;
+            let mut n = 0;
+            for i in 1..nbins {
+                if let Some(b) = &bounds[i-1] {
+                    acc = Some(union_opt(&acc, b));
+                }
+                n += counts[i-1];
+                if n == 0 || right_count[i] == 0 {
+                    continue;
+                }
+                let left_area = acc.as_ref().map_or(0., |b| b.surface_area());
+                let cost = TRAVERSAL_COST + INTERSECT_COST*(n as f64*left_area + right_count[i] as f64*right_area[i])/area;
+                if best.is_none_or(|(c, _, _)| cost < c) {
+                    best = Some((cost, axis, i));
+                }
+            }
+        }
+        let (cost, axis, split) = match best {
+            Some(b) => b,
+            None => return Err(items),
+        };
+        let leaf_cost = INTERSECT_COST*items.len() as f64;
+        if items.len() <= self.max_leaf_size && leaf_cost <= cost {
+            return Err(items);
+        }
+        let (left, right): (Vec<Item>, Vec<Item>) = items.into_iter().partition(|(_, b)| bin_of(b, axis) < split);
+        Ok((left, right))
+    }
+}
+
+impl Default for BvhBuilder {
+    fn default() -> BvhBuilder {
+        BvhBuilder::sah()
+    }
+}
+
+impl BvhNode {
+    pub fn new(objects: Objects, t0: f64, t1: f64) -> BvhNode {
+        BvhNode::with_builder(objects, t0, t1, &BvhBuilder::default())
+    }
+    pub fn with_builder(objects: Objects, t0: f64, t1: f64, builder: &BvhBuilder) -> BvhNode {
+        let shapes = objects.object.into_iter().map(Arc::from).collect();
+        builder.build(shapes, t0, t1)
+    }
+    pub fn from_shapes(shapes: Vec<Arc<dyn Shape>>, t0: f64, t1: f64) -> BvhNode {
+        BvhBuilder::default().build(shapes, t0, t1)
+    }
+    fn leaf(bbox: AABB, items: Vec<Item>) -> BvhNode {
+        let shapes = items.into_iter().map(|(s, _)| s).collect();
+        BvhNode{bbox, content:BvhContent::Leaf(shapes)}
+    }
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats{depth:0, nodes:0, leaves:0, primitives:0, sah_cost:0.};
+        let root_area = self.bbox.surface_area();
+        self.collect_stats(&mut stats, 1, root_area);
+        stats
+    }
+    fn collect_stats(&self, stats: &mut BvhStats, depth: usize, root_area: f64) {
+        stats.nodes += 1;
+        stats.depth = usize::max(stats.depth, depth);
+        // A flat root box (a single rect, say) has no area, so every node counts fully.
+        let rel_area = if root_area > 0. {self.bbox.surface_area()/root_area} else {1.};
+        match &self.content {
+            BvhContent::Leaf(shapes) => {
+                stats.leaves += 1;
+                stats.primitives += shapes.len();
+                stats.sah_cost += rel_area*INTERSECT_COST*shapes.len() as f64;
+            }
+            BvhContent::Branch(left, right) => {
+                stats.sah_cost += rel_area*TRAVERSAL_COST;
+                left.collect_stats(stats, depth + 1, root_area);
+                right.collect_stats(stats, depth + 1, root_area);
+            }
+        }
+    }
+}
+
+fn enclose(items: &[Item]) -> AABB {
+    let mut bbox = items[0].1.clone();
+    for (_, b) in &items[1..] {
+        bbox = AABB::union_box(&bbox, b);
+    }
+    bbox
+}
+
+fn union_opt(a: &Option<AABB>, b: &AABB) -> AABB {
+    match a {
+        Some(a) => AABB::union_box(a, b),
+        None => b.clone(),
+    }
+}
+
+impl Shape for BvhNode {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        if !self.bbox.hit(r, tmin, tmax) {
+            return Hit::miss();
+        }
+        match &self.content {
+            BvhContent::Leaf(shapes) => {
+                let mut curr_hit = Hit::miss();
+                for shape in shapes {
+                    let hit = shape.hit(r, tmin, f64::min(tmax, curr_hit.t));
+                    if hit.h && hit.t < curr_hit.t {
+                        curr_hit = hit;
+                    }
+                }
+                curr_hit
+            }
+            BvhContent::Branch(left, right) => {
+                let hit_left = left.hit(r, tmin, tmax);
+                let tmax = if hit_left.h {hit_left.t} else {tmax};
+                let hit_right = right.hit(r, tmin, tmax);
+                if hit_right.h {hit_right} else {hit_left}
+            }
+        }
+    }
+    fn bound(&self, _: f64, _: f64) -> AABB {
+        self.bbox.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vec3d;
+    use crate::color::Spectrum;
+    use crate::shape::Sphere;
+    use crate::material::Lambertian;
+    use crate::sampler::{IndependentSampler, Sampler};
+
+    // `n` spheres of random sizes in a 10 unit cube, the same ones for the same seed.
+    fn random_spheres(seed: u64, n: usize) -> Objects {
+        let mut sampler = IndependentSampler::new(seed);
+        let mat = Arc::new(Lambertian::new(Spectrum::white()));
+        let spheres = (0..n).map(|_| {
+            let center = Vec3d::rand_vec(-5., 5., &mut sampler);
+            let radius = sampler.gen_range(0.05, 1.);
+            Box::new(Sphere{center, radius, mat:mat.clone()}) as Box<dyn Shape>
+        }).collect();
+        Objects::new(spheres)
+    }
+
+    #[test]
+    fn bvh_hits_what_the_list_hits() {
+        let list = random_spheres(1, 200);
+        let builders = [BvhBuilder::median(), BvhBuilder::sah(), BvhBuilder::new(SplitMethod::Sah, 8, 4)];
+        let bvhs: Vec<BvhNode> = builders.iter().map(|b| BvhNode::with_builder(random_spheres(1, 200), 0., 1., b)).collect();
+        let mut sampler = IndependentSampler::new(2);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let r = Ray::new(Vec3d::rand_vec(-8., 8., &mut sampler), Vec3d::rand_vec(-1., 1., &mut sampler), 0.);
+            let expected = list.hit(&r, 0.001, f64::INFINITY);
+            hits += expected.h as usize;
+            for bvh in &bvhs {
+                let hit = bvh.hit(&r, 0.001, f64::INFINITY);
+                assert_eq!(hit.h, expected.h);
+                if hit.h {
+                    assert_eq!(hit.t, expected.t);
+                    assert_eq!((hit.p.x, hit.p.y, hit.p.z), (expected.p.x, expected.p.y, expected.p.z));
+                    assert_eq!((hit.n.x, hit.n.y, hit.n.z), (expected.n.x, expected.n.y, expected.n.z));
+                }
+            }
+        }
+        assert!(hits > 200, "only {} rays hit", hits);
+    }
+
+    #[test]
+    fn median_split_survives_nan_centroids() {
+        let mut objects = random_spheres(3, 8);
+        // Its bound runs from -inf to inf, so its centroid is NaN.
+        objects.object.push(Box::new(Sphere{center:Vec3d::zero(), radius:f64::INFINITY, mat:Arc::new(Lambertian::new(Spectrum::white()))}));
+        let bvh = BvhNode::with_builder(objects, 0., 1., &BvhBuilder::median());
+        assert_eq!(bvh.stats().primitives, 9);
+    }
+
+    // Spheres of `radius` along the x axis, one at each of `xs`.
+    fn spheres_at(xs: &[f64], radius: f64) -> Objects {
+        let mat = Arc::new(Lambertian::new(Spectrum::white()));
+        Objects::new(xs.iter().map(|&x| Box::new(Sphere{center:Vec3d::new(x, 0., 0.), radius, mat:mat.clone()}) as Box<dyn Shape>).collect())
+    }
+
+    fn largest_leaf(node: &BvhNode) -> usize {
+        match &node.content {
+            BvhContent::Leaf(shapes) => shapes.len(),
+            BvhContent::Branch(left, right) => usize::max(largest_leaf(left), largest_leaf(right)),
+        }
+    }
+
+    #[test]
+    fn stats_count_nodes_and_depth() {
+        let one = BvhNode::with_builder(spheres_at(&[0.], 1.), 0., 1., &BvhBuilder::median());
+        let stats = one.stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.depth, stats.primitives), (1, 1, 1, 1));
+        assert_eq!(stats.sah_cost, INTERSECT_COST);
+        // Four in a row split two and two, then one and one.
+        let four = BvhNode::with_builder(spheres_at(&[0., 10., 20., 30.], 1.), 0., 1., &BvhBuilder::median());
+        let stats = four.stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.depth, stats.primitives), (7, 4, 3, 4));
+    }
+
+    #[test]
+    fn sah_is_no_worse_than_median_under_a_ground_sphere() {
+        // The layout of the random scene: a huge ground sphere under a grid of small ones.
+        let mat = Arc::new(Lambertian::new(Spectrum::white()));
+        let build = |builder: &BvhBuilder| {
+            let mut sampler = IndependentSampler::new(4);
+            let mut world: Vec<Box<dyn Shape>> = vec![Box::new(Sphere{center:Vec3d::new(0., -1000., 0.), radius:1000., mat:mat.clone()})];
+            for a in -5..6 {
+                for b in -5..6 {
+                    let center = Vec3d::new(a as f64 + 0.9*sampler.gen_range(0., 1.), 0.2, b as f64 + 0.9*sampler.gen_range(0., 1.));
+                    world.push(Box::new(Sphere{center, radius:0.2, mat:mat.clone()}));
+                }
+            }
+            BvhNode::with_builder(Objects::new(world), 0., 1., builder).stats()
+        };
+        let median = build(&BvhBuilder::median());
+        let sah = build(&BvhBuilder::sah());
+        assert_eq!(median.primitives, 122);
+        assert_eq!(sah.primitives, 122);
+        assert!(sah.sah_cost <= median.sah_cost, "sah {} against median {}", sah.sah_cost, median.sah_cost);
+    }
+
+    #[test]
+    fn builders_keep_to_leaf_size_and_bins() {
+        for method in [SplitMethod::Median, SplitMethod::Sah] {
+            for leaf in [1, 3, 8] {
+                let bvh = BvhNode::with_builder(random_spheres(5, 100), 0., 1., &BvhBuilder::new(method, leaf, 16));
+                assert!(largest_leaf(&bvh) <= leaf, "{:?} leaves of {} over {}", method, largest_leaf(&bvh), leaf);
+                assert_eq!(bvh.stats().primitives, 100);
+            }
+        }
+        assert_eq!(BvhBuilder::new(SplitMethod::Sah, 0, 0).bins, 2);
+        assert_eq!(BvhBuilder::new(SplitMethod::Sah, 0, 0).max_leaf_size, 1);
+        // Two bins can only split at the middle of the centroids, here between 6 and 7.
+        let xs = [0., 1., 2., 3., 4., 5., 6., 7.5, 14.];
+        let bvh = BvhNode::with_builder(spheres_at(&xs, 0.1), 0., 1., &BvhBuilder::new(SplitMethod::Sah, 1, 2));
+        match &bvh.content {
+            BvhContent::Branch(left, right) => {
+                assert_eq!(left.stats().primitives, 7);
+                assert_eq!(right.stats().primitives, 2);
+            }
+            BvhContent::Leaf(_) => panic!("expected a split"),
+        }
+    }
+}
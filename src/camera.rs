@@ -1,7 +1,6 @@
 use crate::vector::Vec3d;
 use crate::ray::Ray;
-extern crate rand;
-use rand::Rng;
+use crate::sampler::Sampler;
 
 #[derive(Clone, Debug)]
 pub struct Camera {
@@ -18,6 +17,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(look_from:Vec3d, look_at:Vec3d, up: Vec3d, vfov: f64, aspect_ratio: f64, aperture: f64, focus_length: f64, t0:f64, t1: f64) -> Camera {
         let theta = vfov*std::f64::consts::PI/180.;
         let h = (theta / 2.).tan();
@@ -35,10 +35,10 @@ impl Camera {
         let lens_radius = aperture / 2.;
         Camera{origin, horizontal, vertical, lower_left_corner, u, v, w, lens_radius, t0, t1}
     }
-    pub fn get_ray(&self, s:f64, t:f64) ->Ray {
-        let rd = self.lens_radius*Vec3d::rand_in_unit_disk();
+    pub fn get_ray(&self, s:f64, t:f64, sampler: &mut dyn Sampler) ->Ray {
+        let rd = self.lens_radius*Vec3d::rand_in_unit_disk(sampler);
         let offset = self.u*rd.x + self.v*rd.y;
-        let time = rand::thread_rng().gen_range(self.t0, self.t1);
+        let time = sampler.gen_range(self.t0, self.t1);
         Ray::new(self.origin + offset, self.lower_left_corner + s*self.horizontal + t*self.vertical - self.origin - offset, time)
     }
 }
\ No newline at end of file
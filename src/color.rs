@@ -1,4 +1,5 @@
 use crate::vector::Vec3d;
+use std::ops;
 extern crate bmp;
 
 #[derive(Clone, Copy, Debug)]
@@ -53,6 +54,122 @@ impl RGB
 
 }
 
+// Linear, unclamped RGB used for radiance and reflectance in light transport.
+// It is only clamped to an `RGB` once the final pixel value is known.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Spectrum
+{
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+}
+
+impl Spectrum
+{
+    pub fn new(r: f64, g: f64, b: f64) -> Spectrum
+    {
+        Spectrum { r, g, b }
+    }
+    pub fn black() -> Spectrum
+    {
+        Spectrum::new(0., 0., 0.)
+    }
+    pub fn white() -> Spectrum
+    {
+        Spectrum::new(1., 1., 1.)
+    }
+    pub fn from_vec(v: Vec3d) -> Spectrum
+    {
+        Spectrum::new(v.x, v.y, v.z)
+    }
+    pub fn to_vec(&self) -> Vec3d {
+        Vec3d::new(self.r, self.g, self.b)
+    }
+    pub fn to_rgb(self) -> RGB {
+        RGB::new(self.r, self.g, self.b)
+    }
+    pub fn is_black(&self) -> bool {
+        self.r == 0. && self.g == 0. && self.b == 0.
+    }
+    pub fn max_comp(&self) -> f64 {
+        f64::max(self.r, f64::max(self.g, self.b))
+    }
+    pub fn luminance(&self) -> f64 {
+        0.2126*self.r + 0.7152*self.g + 0.0722*self.b
+    }
+}
+
+impl From<RGB> for Spectrum
+{
+    fn from(c: RGB) -> Spectrum
+    {
+        Spectrum::new(c.r, c.g, c.b)
+    }
+}
+
+impl ops::Add for Spectrum
+{
+    type Output = Spectrum;
+    fn add(self, other: Spectrum) -> Spectrum
+    {
+        Spectrum{ r: self.r+other.r, g: self.g+other.g, b: self.b+other.b }
+    }
+}
+
+impl ops::AddAssign for Spectrum
+{
+    fn add_assign(&mut self, other: Spectrum)
+    {
+        *self = *self + other;
+    }
+}
+
+impl ops::Sub for Spectrum
+{
+    type Output = Spectrum;
+    fn sub(self, other: Spectrum) -> Spectrum
+    {
+        Spectrum{ r: self.r-other.r, g: self.g-other.g, b: self.b-other.b }
+    }
+}
+
+impl ops::Mul for Spectrum
+{
+    type Output = Spectrum;
+    fn mul(self, other: Spectrum) -> Spectrum
+    {
+        Spectrum{ r: self.r*other.r, g: self.g*other.g, b: self.b*other.b }
+    }
+}
+
+impl ops::Mul<f64> for Spectrum
+{
+    type Output = Spectrum;
+    fn mul(self, s: f64) -> Spectrum
+    {
+        Spectrum{ r: self.r*s, g: self.g*s, b: self.b*s }
+    }
+}
+
+impl ops::Mul<Spectrum> for f64
+{
+    type Output = Spectrum;
+    fn mul(self, c: Spectrum) -> Spectrum
+    {
+        Spectrum{ r: c.r*self, g: c.g*self, b: c.b*self }
+    }
+}
+
+impl ops::Div<f64> for Spectrum
+{
+    type Output = Spectrum;
+    fn div(self, s: f64) -> Spectrum
+    {
+        Spectrum{ r: self.r/s, g: self.g/s, b: self.b/s }
+    }
+}
+
+#[allow(clippy::needless_return)]
 fn clamp(v: f64, down: f64, up: f64) -> f64
 {
     if v > up
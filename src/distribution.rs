@@ -0,0 +1,121 @@
+// Piecewise constant density over [0, 1), one piece per entry of `func`. There is none without
+// any entries.
+#[derive(Clone, Debug)]
+pub struct Distribution1d {
+    pub func: Vec<f64>,
+    pub cdf: Vec<f64>,
+    pub integral: f64,
+}
+
+impl Distribution1d {
+    pub fn new(func: Vec<f64>) -> Option<Distribution1d> {
+        let n = func.len();
+        if n == 0 {
+            return None;
+        }
+        let mut cdf = vec![0.; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i].abs()/n as f64;
+        }
+        let integral = cdf[n];
+        // Nothing to go by, so every piece is as likely as the others.
+        if integral <= 0. {
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64/n as f64;
+            }
+        }
+        else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Some(Distribution1d{func, cdf, integral})
+    }
+    pub fn len(&self) -> usize {
+        self.func.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.func.is_empty()
+    }
+    // Returns x in [0, 1), its density and the piece it fell in.
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.len();
+        let k = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(n - 1);
+        let width = self.cdf[k + 1] - self.cdf[k];
+        let du = if width > 0. {(u - self.cdf[k])/width} else {0.};
+        let x = ((k as f64 + du)/n as f64).min(1. - f64::EPSILON);
+        (x, self.pdf_at(k), k)
+    }
+    pub fn pdf(&self, x: f64) -> f64 {
+        let n = self.len();
+        self.pdf_at(usize::min((x.max(0.)*n as f64) as usize, n - 1))
+    }
+    fn pdf_at(&self, k: usize) -> f64 {
+        if self.integral > 0. {self.func[k].abs()/self.integral} else {1.}
+    }
+}
+
+// Piecewise constant density over [0, 1)^2 from `nu` by `nv` values, row by row in v: first a
+// row is picked from the marginal density, then a column from that row's conditional density.
+// There is none when either side is zero or `func` is short of `nu` by `nv` values.
+#[derive(Clone, Debug)]
+pub struct Distribution2d {
+    pub conditional: Vec<Distribution1d>,
+    pub marginal: Distribution1d,
+}
+
+impl Distribution2d {
+    pub fn new(func: &[f64], nu: usize, nv: usize) -> Option<Distribution2d> {
+        if func.len() < nu*nv {
+            return None;
+        }
+        let conditional = (0..nv).map(|v| Distribution1d::new(func[v*nu..(v + 1)*nu].to_vec())).collect::<Option<Vec<_>>>()?;
+        let marginal = Distribution1d::new(conditional.iter().map(|d| d.integral).collect())?;
+        Some(Distribution2d{conditional, marginal})
+    }
+    // Returns (u, v) and its density.
+    pub fn sample(&self, u1: f64, u2: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, k) = self.marginal.sample(u2);
+        let (u, pdf_u, _) = self.conditional[k].sample(u1);
+        ((u, v), pdf_u*pdf_v)
+    }
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let nv = self.conditional.len();
+        let k = usize::min((v.max(0.)*nv as f64) as usize, nv - 1);
+        self.marginal.pdf(v)*self.conditional[k].pdf(u)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_input_has_no_distribution() {
+        assert!(Distribution1d::new(vec![]).is_none());
+        assert!(Distribution2d::new(&[], 0, 0).is_none());
+        assert!(Distribution2d::new(&[], 4, 0).is_none());
+        assert!(Distribution2d::new(&[], 0, 4).is_none());
+        assert!(Distribution2d::new(&[1., 2., 3.], 2, 2).is_none());
+    }
+
+    #[test]
+    fn samples_follow_the_function() {
+        let d = Distribution1d::new(vec![1., 3.]).unwrap();
+        assert_eq!(d.integral, 2.);
+        let (x, pdf, k) = d.sample(0.5);
+        assert_eq!(k, 1);
+        assert!((x - 0.5 - 1./6.).abs() < 1e-12);
+        assert_eq!(pdf, 1.5);
+        assert_eq!(d.pdf(0.25), 0.5);
+    }
+
+    #[test]
+    fn all_zero_is_uniform() {
+        let d = Distribution1d::new(vec![0.; 4]).unwrap();
+        let (x, pdf, k) = d.sample(0.6);
+        assert_eq!(k, 2);
+        assert!((x - 0.6).abs() < 1e-12);
+        assert_eq!(pdf, 1.);
+    }
+}
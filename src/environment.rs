@@ -0,0 +1,252 @@
+use crate::vector::Vec3d;
+use crate::color::Spectrum;
+use crate::texture::{ImageTexture, Texture};
+use crate::transform::Transform;
+use crate::sampler::Sampler;
+use crate::distribution::Distribution2d;
+use crate::pdf::{Pdf, UniformSpherePdf};
+use crate::sky::PreethamSky;
+use std::f64::consts::PI;
+
+// Radiance arriving from far away, by direction. Directions are unit length.
+// Maps that know where their light comes from override the sampling, the rest sample every direction alike.
+pub trait EnvMap: Send + Sync {
+    fn radiance(&self, dir: Vec3d) -> Spectrum;
+    fn is_black(&self) -> bool {
+        false
+    }
+    fn sample_direction(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        UniformSpherePdf{}.generate(sampler)
+    }
+    fn pdf(&self, dir: Vec3d) -> f64 {
+        UniformSpherePdf{}.value(dir)
+    }
+}
+
+pub struct ConstantMap {
+    pub color: Spectrum,
+}
+
+impl EnvMap for ConstantMap {
+    fn radiance(&self, _dir: Vec3d) -> Spectrum {
+        self.color
+    }
+    fn is_black(&self) -> bool {
+        self.color.is_black()
+    }
+}
+
+// Blends from `bottom` straight down to `top` straight up.
+pub struct GradientMap {
+    pub bottom: Spectrum,
+    pub top: Spectrum,
+}
+
+impl GradientMap {
+    pub fn sky() -> GradientMap {
+        GradientMap{bottom:Spectrum::white(), top:Spectrum::new(0.5, 0.7, 1.)}
+    }
+}
+
+impl EnvMap for GradientMap {
+    fn radiance(&self, dir: Vec3d) -> Spectrum {
+        let t = 0.5*(dir.y + 1.);
+        (1.-t)*self.bottom + t*self.top
+    }
+}
+
+// A latitude-longitude image, sampled in proportion to the luminance of its pixels.
+// An empty image has no distribution and is black.
+pub struct ImageMap {
+    pub image: ImageTexture,
+    pub distribution: Option<Distribution2d>,
+}
+
+impl ImageMap {
+    pub fn new(image: ImageTexture) -> ImageMap {
+        let (nu, nv) = (image.width as usize, image.height as usize);
+        let distribution = latlong_distribution(nu, nv, |u, v| image.value(u, v, Vec3d::zero()).luminance());
+        ImageMap{image, distribution}
+    }
+}
+
+impl EnvMap for ImageMap {
+    fn radiance(&self, dir: Vec3d) -> Spectrum {
+        if self.distribution.is_none() {
+            return Spectrum::black();
+        }
+        let (u, v) = direction_to_uv(dir);
+        self.image.value(u, v, dir)
+    }
+    fn is_black(&self) -> bool {
+        self.distribution.as_ref().is_none_or(|d| d.marginal.integral <= 0.)
+    }
+    fn sample_direction(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        match &self.distribution {
+            Some(d) => latlong_sample(d, sampler),
+            None => UniformSpherePdf{}.generate(sampler),
+        }
+    }
+    fn pdf(&self, dir: Vec3d) -> f64 {
+        match &self.distribution {
+            Some(d) => latlong_pdf(d, dir),
+            None => UniformSpherePdf{}.value(dir),
+        }
+    }
+}
+
+// Density over the `nu` by `nv` cells of the (u, v) square, following `f` at the cell centers.
+// Rows near the poles cover less solid angle, so they are weighted by sin(theta).
+// There is none without any cells.
+pub fn latlong_distribution<F: Fn(f64, f64) -> f64>(nu: usize, nv: usize, f: F) -> Option<Distribution2d> {
+    let mut func = vec![0.; nu*nv];
+    for j in 0..nv {
+        let v = (j as f64 + 0.5)/nv as f64;
+        let sin_theta = (v*PI).sin();
+        for i in 0..nu {
+            let u = (i as f64 + 0.5)/nu as f64;
+            func[j*nu + i] = f(u, v)*sin_theta;
+        }
+    }
+    Distribution2d::new(&func, nu, nv)
+}
+
+pub fn latlong_sample(distribution: &Distribution2d, sampler: &mut dyn Sampler) -> Vec3d {
+    let (u1, u2) = sampler.get_2d();
+    let ((u, v), _) = distribution.sample(u1, u2);
+    uv_to_direction(u, v)
+}
+
+// The square covers 2pi by pi radians, and a cell at polar angle theta is shrunk by sin(theta).
+pub fn latlong_pdf(distribution: &Distribution2d, dir: Vec3d) -> f64 {
+    let (u, v) = direction_to_uv(dir);
+    let sin_theta = (v*PI).sin();
+    if sin_theta <= 0. {
+        return 0.;
+    }
+    distribution.pdf(u, v)/(2.*PI*PI*sin_theta)
+}
+
+// `Sphere::get_sphere_uv` mirrored in u, since the environment is seen from inside.
+// v goes from straight down to straight up.
+pub fn direction_to_uv(dir: Vec3d) -> (f64, f64) {
+    let theta = (-dir.y).clamp(-1., 1.).acos();
+    let phi = dir.z.atan2(dir.x) + PI;
+    (phi/(2.*PI), theta/PI)
+}
+
+pub fn uv_to_direction(u: f64, v: f64) -> Vec3d {
+    let (theta, phi) = (v*PI, u*2.*PI - PI);
+    let s = theta.sin();
+    Vec3d::new(s*phi.cos(), -theta.cos(), s*phi.sin())
+}
+
+// What rays that leave the scene see: a map, turned by `transform` and scaled by `intensity`.
+// Direct lighting samples it only when `sampled` is set. Image maps and daylight are by
+// default, while constant and gradient maps are left to paths that escape unless asked for.
+pub struct Environment {
+    pub map: Box<dyn EnvMap>,
+    pub transform: Transform,
+    pub intensity: f64,
+    pub sampled: bool,
+}
+
+impl Environment {
+    pub fn new(map: Box<dyn EnvMap>) -> Environment {
+        Environment{map, transform:Transform::identity(), intensity:1., sampled:false}
+    }
+    pub fn constant(color: Spectrum) -> Environment {
+        Environment::new(Box::new(ConstantMap{color}))
+    }
+    pub fn black() -> Environment {
+        Environment::constant(Spectrum::black())
+    }
+    pub fn sky() -> Environment {
+        Environment::new(Box::new(GradientMap::sky()))
+    }
+    pub fn image(image: ImageTexture) -> Environment {
+        Environment::new(Box::new(ImageMap::new(image))).with_sampling(true)
+    }
+    pub fn daylight(turbidity: f64, sun_elevation: f64, sun_azimuth: f64, ground_albedo: Spectrum) -> Environment {
+        Environment::new(Box::new(PreethamSky::new(turbidity, sun_elevation, sun_azimuth, ground_albedo))).with_sampling(true)
+    }
+    pub fn rotate(mut self, axis: Vec3d, degrees: f64) -> Environment {
+        self.transform = self.transform.then(Transform::rotate(axis, degrees));
+        self
+    }
+    pub fn with_intensity(mut self, intensity: f64) -> Environment {
+        self.intensity = intensity;
+        self
+    }
+    pub fn with_sampling(mut self, sampled: bool) -> Environment {
+        self.sampled = sampled;
+        self
+    }
+    pub fn radiance(&self, dir: Vec3d) -> Spectrum {
+        self.intensity*self.map.radiance(self.transform.inv.vector(dir).norm())
+    }
+    // Whether direct lighting samples it: asked to, and giving off any light.
+    pub fn is_light(&self) -> bool {
+        self.sampled && self.intensity > 0. && !self.map.is_black()
+    }
+    pub fn sample_direction(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        self.transform.vector(self.map.sample_direction(sampler))
+    }
+    // Rotations keep solid angle, so the density is the map's.
+    pub fn pdf(&self, dir: Vec3d) -> f64 {
+        self.map.pdf(self.transform.inv.vector(dir).norm())
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::sky()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_asked_for_environments_are_sampled() {
+        assert!(!Environment::default().is_light());
+        assert!(!Environment::constant(Spectrum::white()).is_light());
+        assert!(Environment::sky().with_sampling(true).is_light());
+        assert!(!Environment::black().with_sampling(true).is_light());
+        assert!(Environment::daylight(3., 30., 0., Spectrum::new(0.2, 0.2, 0.2)).is_light());
+    }
+
+    #[test]
+    fn empty_image_is_black() {
+        let image = ImageTexture{data:bmp::Image::new(0, 0), width:0, height:0};
+        let env = Environment::image(image);
+        assert!(!env.is_light());
+        assert!(env.radiance(Vec3d::new(0., 1., 0.)).is_black());
+    }
+
+    #[test]
+    fn uv_round_trips_through_directions() {
+        for &(u, v) in &[(0.1, 0.2), (0.5, 0.5), (0.75, 0.9), (0.3, 0.01)] {
+            let dir = uv_to_direction(u, v);
+            assert!((dir.len() - 1.).abs() < 1e-12);
+            let (u2, v2) = direction_to_uv(dir);
+            assert!((u - u2).abs() < 1e-12 && (v - v2).abs() < 1e-12, "({}, {}) came back as ({}, {})", u, v, u2, v2);
+        }
+        assert!((uv_to_direction(0.3, 0.).y + 1.).abs() < 1e-12);
+        assert!((uv_to_direction(0.3, 1.).y - 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn turned_and_scaled_maps_are_seen_turned_and_scaled() {
+        let (up, down) = (Vec3d::new(0., 1., 0.), Vec3d::new(0., -1., 0.));
+        let env = Environment::sky();
+        assert_eq!(env.radiance(up), GradientMap::sky().top);
+        assert_eq!(env.radiance(down), GradientMap::sky().bottom);
+        // Upside down the top of the map is below.
+        let turned = Environment::sky().rotate(Vec3d::new(1., 0., 0.), 180.).with_intensity(2.);
+        let below = turned.radiance(down);
+        let top = 2.*GradientMap::sky().top;
+        assert!((below.r - top.r).abs() < 1e-12 && (below.g - top.g).abs() < 1e-12 && (below.b - top.b).abs() < 1e-12, "{:?}", below);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::color::Spectrum;
+use crate::filter::Filter;
+use crate::tonemap::PostProcess;
+use crate::texture::ColorRamp;
+extern crate bmp;
+use bmp::Image;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Pixels [x0, x1) by [y0, y1), with y counted from the bottom of the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tile {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Tile {
+    pub fn width(&self) -> u32 {
+        self.x1.saturating_sub(self.x0)
+    }
+    pub fn height(&self) -> u32 {
+        self.y1.saturating_sub(self.y0)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.width() == 0 || self.height() == 0
+    }
+    // The pixels in both, an empty tile if they do not overlap.
+    pub fn intersect(&self, other: &Tile) -> Tile {
+        let x0 = u32::max(self.x0, other.x0);
+        let y0 = u32::max(self.y0, other.y0);
+        Tile{x0, y0, x1:u32::max(u32::min(self.x1, other.x1), x0), y1:u32::max(u32::min(self.y1, other.y1), y0)}
+    }
+    // Grown by `n` pixels on every side, stopping at zero.
+    pub fn grow(&self, n: u32) -> Tile {
+        Tile{x0:self.x0.saturating_sub(n), y0:self.y0.saturating_sub(n), x1:self.x1 + n, y1:self.y1 + n}
+    }
+}
+
+// Filter weighted sums of linear samples over `region` of a `width` by `height` image, row-major
+// with `j` counted from the bottom, and the samples each pixel took. Pixel (i, j) covers
+// [i, i+1) by [j, j+1). Workers fill a film per tile, reaching as far as their filter does,
+// and the films are merged into the whole image, or into a crop window of it.
+// Nothing is tone mapped or quantized until the film is written out.
+#[derive(Clone, Debug)]
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub region: Tile,
+    pub sums: Vec<Spectrum>,
+    pub weights: Vec<f64>,
+    pub samples: Vec<u32>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film::with_region(width, height, Tile{x0:0, y0:0, x1:width, y1:height})
+    }
+    pub fn with_region(width: u32, height: u32, region: Tile) -> Film {
+        let region = region.intersect(&Tile{x0:0, y0:0, x1:width, y1:height});
+        let n = (region.width()*region.height()) as usize;
+        Film{width, height, region, sums:vec![Spectrum::black(); n], weights:vec![0.; n], samples:vec![0; n]}
+    }
+    fn index(&self, i: u32, j: u32) -> Option<usize> {
+        let r = &self.region;
+        if i < r.x0 || i >= r.x1 || j < r.y0 || j >= r.y1 {
+            return None;
+        }
+        Some(((j - r.y0)*r.width() + i - r.x0) as usize)
+    }
+    // Adds a sample taken at film position (x, y) to every pixel of the region the filter reaches.
+    pub fn add_sample(&mut self, x: f64, y: f64, c: Spectrum, filter: &dyn Filter) {
+        let radius = filter.radius();
+        let r = &self.region;
+        let i0 = f64::max((x - 0.5 - radius).ceil(), r.x0 as f64) as u32;
+        let i1 = f64::min((x - 0.5 + radius).floor(), r.x1 as f64 - 1.);
+        let j0 = f64::max((y - 0.5 - radius).ceil(), r.y0 as f64) as u32;
+        let j1 = f64::min((y - 0.5 + radius).floor(), r.y1 as f64 - 1.);
+        if i1 < 0. || j1 < 0. {
+            return;
+        }
+        for j in j0..=j1 as u32 {
+            for i in i0..=i1 as u32 {
+                let w = filter.eval(x - (i as f64 + 0.5), y - (j as f64 + 0.5));
+                if w == 0. {
+                    continue;
+                }
+                if let Some(k) = self.index(i, j) {
+                    self.sums[k] += w*c;
+                    self.weights[k] += w;
+                }
+            }
+        }
+    }
+    pub fn add_count(&mut self, i: u32, j: u32, n: u32) {
+        if let Some(k) = self.index(i, j) {
+            self.samples[k] += n;
+        }
+    }
+    // Adds in another film of the same image, over the part of its region this one covers.
+    pub fn merge(&mut self, other: &Film) {
+        let r = other.region.intersect(&self.region);
+        for j in r.y0..r.y1 {
+            for i in r.x0..r.x1 {
+                let src = other.index(i, j).unwrap();
+                let k = self.index(i, j).unwrap();
+                self.sums[k] += other.sums[src];
+                self.weights[k] += other.weights[src];
+                self.samples[k] += other.samples[src];
+            }
+        }
+    }
+    // The filtered value of a pixel, black where no weight landed.
+    pub fn pixel(&self, i: u32, j: u32) -> Spectrum {
+        match self.index(i, j) {
+            Some(k) if self.weights[k].abs() > 1e-12 => self.sums[k]/self.weights[k],
+            _ => Spectrum::black(),
+        }
+    }
+    pub fn sample_count(&self, i: u32, j: u32) -> u32 {
+        self.index(i, j).map_or(0, |k| self.samples[k])
+    }
+
+    // Writers all cover just `region`, so a cropped film gives an image the size of the crop.
+
+    // Exposure, tone mapping, clamping and quantization to 8 bits happen only here.
+    pub fn to_image(&self, post: &PostProcess) -> Image {
+        let r = self.region;
+        let mut img = Image::new(r.width(), r.height());
+        for j in r.y0..r.y1 {
+            for i in r.x0..r.x1 {
+                let c = self.pixel(i, j);
+                img.set_pixel(i - r.x0, r.y1-j-1, post.apply(c).pixel());
+            }
+        }
+        img
+    }
+    // Samples taken per pixel, from dark blue for the fewest in the film to red for the most.
+    pub fn heatmap(&self) -> Image {
+        let ramp = ColorRamp::new(vec![
+            (0., Spectrum::new(0., 0., 0.3)),
+            (0.25, Spectrum::new(0., 0.4, 1.)),
+            (0.5, Spectrum::new(0., 0.8, 0.2)),
+            (0.75, Spectrum::new(1., 0.9, 0.)),
+            (1., Spectrum::new(1., 0., 0.))]);
+        let lo = self.samples.iter().copied().min().unwrap_or(0) as f64;
+        let hi = self.samples.iter().copied().max().unwrap_or(0) as f64;
+        let r = self.region;
+        let mut img = Image::new(r.width(), r.height());
+        for j in r.y0..r.y1 {
+            for i in r.x0..r.x1 {
+                let n = self.sample_count(i, j) as f64;
+                let t = if hi > lo {(n - lo)/(hi - lo)} else {0.};
+                img.set_pixel(i - r.x0, r.y1-j-1, ramp.at(t).to_rgb().pixel());
+            }
+        }
+        img
+    }
+    pub fn write_bmp<P: AsRef<Path>>(&self, path: P, post: &PostProcess) -> io::Result<()> {
+        self.to_image(post).save(path)
+    }
+    // Binary PPM (P6): 8 bits per channel after `post`, rows from the top.
+    pub fn write_ppm<P: AsRef<Path>>(&self, path: P, post: &PostProcess) -> io::Result<()> {
+        let r = self.region;
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "P6\n{} {}\n255\n", r.width(), r.height())?;
+        for j in (r.y0..r.y1).rev() {
+            for i in r.x0..r.x1 {
+                let p = post.apply(self.pixel(i, j)).pixel();
+                out.write_all(&[p.r, p.g, p.b])?;
+            }
+        }
+        out.flush()
+    }
+    // Portable float map: the linear pixel values as little-endian 32-bit floats, rows from
+    // the bottom. Nothing is tone mapped, so the film can be graded elsewhere.
+    pub fn write_pfm<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let r = self.region;
+        let mut out = BufWriter::new(File::create(path)?);
+        // A negative scale marks the data as little-endian.
+        write!(out, "PF\n{} {}\n-1.0\n", r.width(), r.height())?;
+        for j in r.y0..r.y1 {
+            for i in r.x0..r.x1 {
+                let c = self.pixel(i, j);
+                for x in [c.r, c.g, c.b] {
+                    out.write_all(&(x as f32).to_le_bytes())?;
+                }
+            }
+        }
+        out.flush()
+    }
+    // Picks the writer from the file extension: bmp, ppm or pfm. `post` does not apply to pfm.
+    pub fn save<P: AsRef<Path>>(&self, path: P, post: &PostProcess) -> io::Result<()> {
+        let path = path.as_ref();
+        let ext = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("bmp") => self.write_bmp(path, post),
+            Some("ppm") => self.write_ppm(path, post),
+            Some("pfm") => self.write_pfm(path),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no film writer for `{}`", path.display()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::BoxFilter;
+    use crate::tonemap::{Encoding, ToneMap};
+    use std::convert::TryInto;
+
+    // A 3 by 2 film whose pixel (i, j) holds i + 10j in every channel.
+    fn numbered(region: Tile) -> Film {
+        let mut film = Film::with_region(3, 2, region);
+        for j in 0..2 {
+            for i in 0..3 {
+                let x = (i + 10*j) as f64;
+                film.add_sample(i as f64 + 0.5, j as f64 + 0.5, Spectrum::new(x, x, x), &BoxFilter::default());
+            }
+        }
+        film
+    }
+
+    fn written<F: Fn(&Path) -> io::Result<()>>(name: &str, write: F) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("film_{}_{}", std::process::id(), name));
+        write(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        bytes
+    }
+
+    #[test]
+    fn pfm_is_little_endian_from_the_bottom_row() {
+        let film = numbered(Tile{x0:0, y0:0, x1:3, y1:2});
+        let bytes = written("test.pfm", |p| film.save(p, &PostProcess::default()));
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let values: Vec<f32> = bytes[header.len()..].chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+        let expected: Vec<f32> = [0., 1., 2., 10., 11., 12.].iter().flat_map(|&x| [x, x, x]).collect();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn ppm_is_from_the_top_row() {
+        let film = numbered(Tile{x0:0, y0:0, x1:3, y1:2});
+        // Scaled down so no pixel saturates.
+        let post = PostProcess::new(-4., ToneMap::Clamp, Encoding::Linear);
+        let bytes = written("test.ppm", |p| film.save(p, &post));
+        let header = b"P6\n3 2\n255\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let reds: Vec<u8> = bytes[header.len()..].iter().step_by(3).copied().collect();
+        let expected: Vec<u8> = [10., 11., 12., 0., 1., 2.].iter().map(|&x| post.apply(Spectrum::new(x, x, x)).pixel().r).collect();
+        assert_eq!(reds, expected);
+        assert_eq!(reds[0], 159);
+    }
+
+    #[test]
+    fn cropped_film_writes_just_the_crop() {
+        let film = numbered(Tile{x0:1, y0:1, x1:3, y1:5});
+        assert_eq!(film.region, Tile{x0:1, y0:1, x1:3, y1:2});
+        let bytes = written("crop.pfm", |p| film.write_pfm(p));
+        let header = b"PF\n2 1\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + 2*3*4);
+        let image = film.to_image(&PostProcess::default());
+        assert_eq!((image.get_width(), image.get_height()), (2, 1));
+        assert!(film.save("crop.png", &PostProcess::default()).is_err());
+    }
+
+    #[test]
+    fn merge_adds_the_overlap() {
+        let left = numbered(Tile{x0:0, y0:0, x1:2, y1:2});
+        let right = numbered(Tile{x0:1, y0:0, x1:3, y1:2});
+        let mut film = Film::new(3, 2);
+        film.merge(&left);
+        film.merge(&right);
+        assert_eq!(film.weights, vec![1., 2., 1., 1., 2., 1.]);
+        // Twice the sum over twice the weight is the same pixel.
+        for j in 0..2 {
+            for i in 0..3 {
+                assert_eq!(film.pixel(i, j).r, (i + 10*j) as f64);
+            }
+        }
+        let mut window = Film::with_region(3, 2, Tile{x0:1, y0:1, x1:2, y1:2});
+        window.merge(&right);
+        assert_eq!(window.sums, vec![Spectrum::new(11., 11., 11.)]);
+    }
+
+    #[test]
+    fn box_filtered_samples_land_in_one_pixel() {
+        let mut film = Film::new(3, 1);
+        // On the edge between pixels 0 and 1, and on the left edge of the image.
+        film.add_sample(1., 0.5, Spectrum::white(), &BoxFilter::default());
+        film.add_sample(0., 0., Spectrum::white(), &BoxFilter::default());
+        film.add_sample(2.999, 0.999, Spectrum::white(), &BoxFilter::default());
+        assert_eq!(film.weights, vec![1., 1., 1.]);
+    }
+}
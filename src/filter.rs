@@ -0,0 +1,225 @@
+use std::f64::consts::PI;
+
+// Weight of a sample at offset (x, y) from a pixel center, in pixels. Zero outside `radius`.
+// Filters with negative lobes sharpen; pixels divide by the sum of their weights.
+pub trait Filter: Send + Sync + std::fmt::Debug {
+    fn radius(&self) -> f64;
+    fn eval(&self, x: f64, y: f64) -> f64;
+}
+
+// Every sample within `radius` counts the same. A radius of 0.5 keeps samples in their own pixel:
+// the box is half-open, so a sample on the edge between two pixels lands in just one of them.
+#[derive(Clone, Copy, Debug)]
+pub struct BoxFilter {
+    pub radius: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        let inside = |t: f64| -self.radius <= t && t < self.radius;
+        if inside(x) && inside(y) {1.} else {0.}
+    }
+}
+
+impl Default for BoxFilter {
+    fn default() -> BoxFilter {
+        BoxFilter{radius:0.5}
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TentFilter {
+    pub radius: f64,
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        f64::max(self.radius - x.abs(), 0.)*f64::max(self.radius - y.abs(), 0.)
+    }
+}
+
+// A Gaussian shifted down so it reaches zero at `radius` instead of being cut off there.
+#[derive(Clone, Copy, Debug)]
+pub struct GaussianFilter {
+    pub radius: f64,
+    pub sigma: f64,
+}
+
+impl GaussianFilter {
+    fn gaussian(&self, x: f64) -> f64 {
+        (-x*x/(2.*self.sigma*self.sigma)).exp()
+    }
+    fn eval_1d(&self, x: f64) -> f64 {
+        f64::max(self.gaussian(x) - self.gaussian(self.radius), 0.)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_1d(x)*self.eval_1d(y)
+    }
+}
+
+// Mitchell and Netravali's cubic, stretched over `radius`. B = C = 1/3 is their recommendation.
+#[derive(Clone, Copy, Debug)]
+pub struct MitchellFilter {
+    pub radius: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl MitchellFilter {
+    fn eval_1d(&self, x: f64) -> f64 {
+        let x = (2.*x/self.radius).abs();
+        let (b, c) = (self.b, self.c);
+        let v = if x >= 2. {0.} else if x > 1. {
+            (-b - 6.*c)*x*x*x + (6.*b + 30.*c)*x*x + (-12.*b - 48.*c)*x + (8.*b + 24.*c)
+        }
+        else {
+            (12. - 9.*b - 6.*c)*x*x*x + (-18. + 12.*b + 6.*c)*x*x + (6. - 2.*b)
+        };
+        v/6.
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_1d(x)*self.eval_1d(y)
+    }
+}
+
+impl Default for MitchellFilter {
+    fn default() -> MitchellFilter {
+        MitchellFilter{radius:2., b:1./3., c:1./3.}
+    }
+}
+
+// Sinc windowed by a wider sinc, `tau` lobes of it across the radius.
+#[derive(Clone, Copy, Debug)]
+pub struct LanczosFilter {
+    pub radius: f64,
+    pub tau: f64,
+}
+
+impl LanczosFilter {
+    fn eval_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        if x >= self.radius {
+            return 0.;
+        }
+        sinc(x)*sinc(x/self.tau)
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_1d(x)*self.eval_1d(y)
+    }
+}
+
+impl Default for LanczosFilter {
+    fn default() -> LanczosFilter {
+        LanczosFilter{radius:3., tau:3.}
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.;
+    }
+    (PI*x).sin()/(PI*x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters() -> Vec<Box<dyn Filter>> {
+        vec![
+            Box::new(BoxFilter::default()),
+            Box::new(BoxFilter{radius:1.5}),
+            Box::new(TentFilter{radius:1.}),
+            Box::new(GaussianFilter{radius:1.5, sigma:0.5}),
+            Box::new(MitchellFilter::default()),
+            Box::new(LanczosFilter::default()),
+            Box::new(LanczosFilter{radius:2., tau:3.}),
+        ]
+    }
+
+    #[test]
+    fn filters_vanish_from_their_radius_on() {
+        for f in filters() {
+            let r = f.radius();
+            for d in [r, r + 1e-9, r + 0.3, 2.*r, 10.*r] {
+                assert_eq!(f.eval(d, 0.), 0., "{:?} at {}", f, d);
+                assert_eq!(f.eval(0., d), 0., "{:?} at {}", f, d);
+                assert_eq!(f.eval(d, d), 0., "{:?} at {}", f, d);
+            }
+            for d in [r + 1e-9, 2.*r] {
+                assert_eq!(f.eval(-d, 0.), 0., "{:?} at {}", f, -d);
+                assert_eq!(f.eval(0., -d), 0., "{:?} at {}", f, -d);
+            }
+            assert!(f.eval(0., 0.) > 0., "{:?}", f);
+        }
+    }
+
+    #[test]
+    fn box_is_half_open() {
+        let f = BoxFilter::default();
+        assert_eq!(f.eval(-0.5, 0.), 1.);
+        assert_eq!(f.eval(0.5, 0.), 0.);
+        assert_eq!(f.eval(0.4999, -0.5), 1.);
+    }
+
+    #[test]
+    fn mitchell_matches_its_polynomials() {
+        let f = MitchellFilter::default();
+        let (b, c) = (f.b, f.c);
+        let at_0 = (6. - 2.*b)/6.;
+        let at_1 = b/6.;
+        // |x| = 1 on the filter's own scale is half the radius.
+        let half = f.radius/2.;
+        assert!((f.eval(0., 0.) - at_0*at_0).abs() < 1e-12);
+        assert!((f.eval(half, 0.) - at_1*at_0).abs() < 1e-12);
+        assert!((f.eval(-half, half) - at_1*at_1).abs() < 1e-12);
+        // Both pieces meet at |x| = 1.
+        let eps = 1e-7;
+        assert!((f.eval(half - eps, 0.) - f.eval(half + eps, 0.)).abs() < 1e-6);
+        // The outer piece between 1 and 2: 1/6 (-B - 6C) x^3 + ... at x = 1.5.
+        let x = 1.5;
+        let outer = ((-b - 6.*c)*x*x*x + (6.*b + 30.*c)*x*x + (-12.*b - 48.*c)*x + (8.*b + 24.*c))/6.;
+        assert!((f.eval(0.75*f.radius, 0.) - outer*at_0).abs() < 1e-12);
+        assert!(outer < 0.);
+    }
+
+    #[test]
+    fn gaussian_and_lanczos_are_symmetric() {
+        let gaussian = GaussianFilter{radius:1.5, sigma:0.5};
+        let lanczos = LanczosFilter::default();
+        let fs: [&dyn Filter; 2] = [&gaussian, &lanczos];
+        for f in fs {
+            for &(x, y) in &[(0.3, 0.7), (1.2, 0.1), (0.05, 1.4)] {
+                let v = f.eval(x, y);
+                assert_eq!(f.eval(-x, y), v);
+                assert_eq!(f.eval(x, -y), v);
+                assert_eq!(f.eval(-x, -y), v);
+                assert_eq!(f.eval(y, x), v);
+            }
+        }
+    }
+}
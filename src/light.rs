@@ -0,0 +1,65 @@
+use crate::vector::Vec3d;
+use crate::shape::Shape;
+use crate::sampler::Sampler;
+use crate::pdf::Pdf;
+use crate::environment::Environment;
+use std::sync::Arc;
+
+// The emitting shapes of a scene, sampled directly to estimate the light arriving at a point.
+#[derive(Clone, Default)]
+pub struct Lights {
+    pub lights: Vec<Arc<dyn Shape>>,
+}
+
+impl Lights {
+    pub fn new(lights: Vec<Arc<dyn Shape>>) -> Lights {
+        Lights{lights}
+    }
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+}
+
+// Directions from `origin` towards the lights and, if given, the environment. Each of them is
+// picked with the same probability, so the density is the average of theirs.
+pub struct LightPdf<'a> {
+    pub lights: &'a Lights,
+    pub environment: Option<&'a Environment>,
+    pub origin: Vec3d,
+}
+
+impl<'a> LightPdf<'a> {
+    pub fn new(lights: &'a Lights, environment: Option<&'a Environment>, origin: Vec3d) -> LightPdf<'a> {
+        LightPdf{lights, environment, origin}
+    }
+    pub fn len(&self) -> usize {
+        self.lights.len() + self.environment.map_or(0, |_| 1)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> Pdf for LightPdf<'a> {
+    fn value(&self, dir: Vec3d) -> f64 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let mut sum: f64 = self.lights.lights.iter().map(|l| l.pdf_value(self.origin, dir)).sum();
+        if let Some(env) = self.environment {
+            sum += env.pdf(dir);
+        }
+        sum/self.len() as f64
+    }
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        let n = self.len();
+        let k = usize::min((sampler.get_1d()*n as f64) as usize, n - 1);
+        match self.environment {
+            Some(env) if k == self.lights.len() => env.sample_direction(sampler),
+            _ => self.lights.lights[k].random(self.origin, sampler),
+        }
+    }
+}
@@ -3,95 +3,102 @@ use vector::Vec3d;
 pub mod color;
 pub mod ray;
 pub mod shape;
-use crate::shape::Shape;
 pub mod camera;
 pub mod material;
 pub mod aabb;
 pub mod texture;
+pub mod bvh;
+pub mod render;
+pub mod mesh;
+pub mod obj;
+pub mod transform;
+pub mod medium;
+pub mod perlin;
+pub mod pdf;
+pub mod sampler;
+pub mod tonemap;
+pub mod light;
+pub mod scene;
+pub mod environment;
+pub mod distribution;
+pub mod sky;
+pub mod filter;
+pub mod film;
+use sampler::Sampler;
 
-extern crate bmp;
-use bmp::Image;
-extern crate rand;
-use rand::Rng;
 use std::sync::Arc;
 
-fn ray_color(r: &ray::Ray, world: &shape::Objects, depth: i32) -> color::RGB {
-    if depth < 0 {
-        return color::RGB::black();
-    }
-    let hit = world.hit(r, 0.0001, f64::INFINITY);      
-    if hit.h == true {
-        let scatter = hit.mat.scatter(&r, &hit);
-        if scatter.s == true {
-            return (scatter.a.to_vec() * ray_color(&scatter.r, &world, depth - 1).to_vec()).to_rgb()
-        }
-        else
-        {
-            return color::RGB::black();
-        }
-    }
-    let unit_dir = r.dir.norm();
-    let t = 0.5*(unit_dir.y + 1.);
-    ((1.-t)*vector::Vec3d::new(1., 1., 1.)+t*vector::Vec3d::new(0.5, 0.7, 1.)).to_rgb()
-}
-
+// Renders the scene named by the first argument, `earth` by default, into test.bmp.
 fn main() {
+    sampler::set_seed(0);
     let aspect_ratio = 16./9.;
-    let img_width = 400 as u32;
+    let img_width = 400_u32;
     let img_height = (img_width as f64/aspect_ratio) as u32;
-    let samples_per_pixel = 100;
-    let max_depth = 20;
-    
-    let look_from = vector::Vec3d::new(13., 2., 3.);
-    let look_at = vector::Vec3d::new(0.,0.,0.);
-    let up = vector::Vec3d::new(0., 1., 0.);
-    let focus_length = 10.;
-    let aperture = 0.1;
-    let cam = camera::Camera::new(look_from, look_at, up, 20., aspect_ratio, aperture, focus_length, 0., 1.);
-    random_scene();
-    two_spheres();
-    let world = earth();
-    let mut img = Image::new(img_width, img_height);
-    for i in 0..img_width {
-        for j in 0..img_height {
-            let mut pixel_color = vector::Vec3d::zero();
-            for _ in 0..samples_per_pixel {
-                let u = (i as f64 + rand::thread_rng().gen_range(0.0, 1.0))/(img_width as f64 - 1.);
-                let v = (j as f64 + rand::thread_rng().gen_range(0.0, 1.0))/(img_height as f64 - 1.);
-                let r = cam.get_ray(u, v);
-                pixel_color = pixel_color + (ray_color(&r, &world, max_depth).to_vec() / samples_per_pixel as f64);
-            }
-            img.set_pixel(i, img_height-j-1, pixel_color.to_rgb().pixel());
+    let settings = render::Settings::new(img_width, img_height, 100, 20);
+
+    let name = std::env::args().nth(1).unwrap_or_else(|| "earth".to_string());
+    let world = match name.as_str() {
+        "random" => random_scene(),
+        "two_spheres" => two_spheres(),
+        "perlin" => two_perlin_spheres(),
+        "earth" => earth(),
+        "simple_light" => simple_light(),
+        "cornell" => cornell_box(),
+        "cornell_smoke" => cornell_smoke(),
+        _ => {
+            eprintln!("Unknown scene `{}`, expected random, two_spheres, perlin, earth, simple_light, cornell or cornell_smoke", name);
+            std::process::exit(1);
         }
-        if (i+1) % 10 == 0 {
-            println!("{0}/{1}", i+1, img_width);
+    };
+    let up = vector::Vec3d::new(0., 1., 0.);
+    let cam = if name.starts_with("cornell") {
+        camera::Camera::new(Vec3d::new(278., 278., -800.), Vec3d::new(278., 278., 0.), up, 40., aspect_ratio, 0., 10., 0., 1.)
+    }
+    else {
+        let look_from = vector::Vec3d::new(13., 2., 3.);
+        let look_at = vector::Vec3d::new(0.,0.,0.);
+        let focus_length = 10.;
+        let aperture = 0.1;
+        camera::Camera::new(look_from, look_at, up, 20., aspect_ratio, aperture, focus_length, 0., 1.)
+    };
+    let mut scene = scene::Scene::new(world, cam.t0, cam.t1);
+    if matches!(name.as_str(), "simple_light" | "cornell" | "cornell_smoke") {
+        scene.environment = environment::Environment::black();
+    }
+    let film = render::render_parallel(&cam, &scene, &settings);
+    if let Err(e) = film.save("test.bmp", &settings.post) {
+        eprintln!("Failed to save test.bmp: {}", e);
+    }
+    if settings.adaptive.is_some() {
+        if let Err(e) = film.heatmap().save("spp.bmp") {
+            eprintln!("Failed to save spp.bmp: {}", e);
         }
     }
-    let _ = img.save("test.bmp");
 }
 
 fn random_scene() -> shape::Objects {
+    let mut sampler = sampler::IndependentSampler::new(sampler::seed());
     let mut world: Vec<Box<dyn shape::Shape>> = Vec::new();
-    let odd = Box::new(texture::SolidTexture{color:color::RGB::new(0.2, 0.3, 0.1)});
-    let even = Box::new(texture::SolidTexture{color:color::RGB::new(0.9, 0.9, 0.9)});
+    let odd = Box::new(texture::SolidTexture{color:color::Spectrum::new(0.2, 0.3, 0.1)});
+    let even = Box::new(texture::SolidTexture{color:color::Spectrum::new(0.9, 0.9, 0.9)});
     let ground_mat = Arc::new(material::Lambertian{albedo:Box::new(texture::CheckerTexture{odd, even})});
     world.push(Box::new(shape::Sphere{center:Vec3d::new(0.,-1000.,0.), radius:1000., mat:ground_mat.clone()}));
     for a in -5..6 {
         for b in -5..6 {
             let radius = 0.2;
-            let choose_mat = rand::thread_rng().gen_range(0.,1.);
-            let center = Vec3d::new(a as f64 + 0.9*rand::thread_rng().gen_range(0.,1.),
+            let choose_mat = sampler.gen_range(0.,1.);
+            let center = Vec3d::new(a as f64 + 0.9*sampler.gen_range(0.,1.),
                                     0.2,
-                                    b as f64 + 0.9*rand::thread_rng().gen_range(0.,1.));
+                                    b as f64 + 0.9*sampler.gen_range(0.,1.));
             if choose_mat < 0.8 {
-                let albedo = Vec3d::rand_vec(0., 1.).to_rgb();
+                let albedo = color::Spectrum::from_vec(Vec3d::rand_vec(0., 1., &mut sampler));
                 let mat = Arc::new(material::Lambertian::new(albedo));
-                let center2 = center + Vec3d::new(0., rand::thread_rng().gen_range(0., 0.5), 0.);
+                let center2 = center + Vec3d::new(0., sampler.gen_range(0., 0.5), 0.);
                 world.push(Box::new(shape::MovingSphere{c0:center, c1:center2, t0:0., t1:1., radius, mat}));
             }
             else if choose_mat < 0.95 {
-                let albedo = Vec3d::rand_vec(0.5, 1.).to_rgb();
-                let fuzz = rand::thread_rng().gen_range(0.,0.5);
+                let albedo = color::Spectrum::from_vec(Vec3d::rand_vec(0.5, 1., &mut sampler));
+                let fuzz = sampler.gen_range(0.,0.5);
                 let mat = Arc::new(material::Metal{albedo, fuzz});
                 world.push(Box::new(shape::Sphere{center, radius, mat}));
             }
@@ -104,21 +111,33 @@ fn random_scene() -> shape::Objects {
     let radius = 1.;
     let mat = Arc::new(material::Dielectric{ir:1.5});
     world.push(Box::new(shape::Sphere{center:Vec3d::new(0.,1.,0.), radius, mat}));
-    let mat = Arc::new(material::Lambertian::new(color::RGB::new(0.4,0.2,0.1)));
+    let mat = Arc::new(material::Lambertian::new(color::Spectrum::new(0.4,0.2,0.1)));
     world.push(Box::new(shape::Sphere{center:Vec3d::new(-4.,1.,0.), radius, mat}));
-    let mat = Arc::new(material::Metal{albedo:color::RGB::new(0.7,0.6,0.5),fuzz:0.});
+    let mat = Arc::new(material::Metal{albedo:color::Spectrum::new(0.7,0.6,0.5),fuzz:0.});
     world.push(Box::new(shape::Sphere{center:Vec3d::new(4.,1.,0.), radius, mat}));
     shape::Objects::new(world)
 }
 
 fn two_spheres() -> shape::Objects {
     let mut world: Vec<Box<dyn shape::Shape>> = Vec::new();
-    let mat = Arc::new(material::Lambertian{albedo:Box::new(texture::CheckerTexture::new(color::RGB::new(0.2,0.3,0.1),color::RGB::white()))});
+    let mat = Arc::new(material::Lambertian{albedo:Box::new(texture::CheckerTexture::new(color::Spectrum::new(0.2,0.3,0.1),color::Spectrum::white()))});
     world.push(Box::new(shape::Sphere{center:vector::Vec3d::new(0.,-10.,0.), radius:10., mat:mat.clone()}));
     world.push(Box::new(shape::Sphere{center:vector::Vec3d::new(0.,10.,0.), radius:10., mat:mat.clone()}));
     shape::Objects::new(world)
 }
 
+fn two_perlin_spheres() -> shape::Objects {
+    let mut sampler = sampler::IndependentSampler::new(sampler::seed());
+    let mut world: Vec<Box<dyn shape::Shape>> = Vec::new();
+    let mut ground = texture::NoiseTexture::new(perlin::Perlin::new(&mut sampler), 4.);
+    ground.octaves = 4;
+    ground.ramp = texture::ColorRamp::new(vec![(0.3, color::Spectrum::new(0.1,0.15,0.05)), (0.7, color::Spectrum::new(0.5,0.6,0.3))]);
+    let marble = texture::MarbleTexture::new(perlin::Perlin::new(&mut sampler), 4.);
+    world.push(Box::new(shape::Sphere{center:vector::Vec3d::new(0.,-1000.,0.), radius:1000., mat:Arc::new(material::Lambertian{albedo:Box::new(ground)})}));
+    world.push(Box::new(shape::Sphere{center:vector::Vec3d::new(0.,2.,0.), radius:2., mat:Arc::new(material::Lambertian{albedo:Box::new(marble)})}));
+    shape::Objects::new(world)
+}
+
 fn earth() -> shape::Objects {
     let mut world: Vec<Box<dyn shape::Shape>> = Vec::new();
     let earth_texture = texture::ImageTexture::new("earthmap.bmp");
@@ -127,6 +146,63 @@ fn earth() -> shape::Objects {
     shape::Objects::new(world)
 }
 
+// Lit only by its emitters, render it with `environment::Environment::black()`.
+fn simple_light() -> shape::Objects {
+    let mut world: Vec<Box<dyn shape::Shape>> = Vec::new();
+    let mat = Arc::new(material::Lambertian{albedo:Box::new(texture::CheckerTexture::new(color::Spectrum::new(0.2,0.3,0.1),color::Spectrum::white()))});
+    world.push(Box::new(shape::Sphere{center:vector::Vec3d::new(0.,-1000.,0.), radius:1000., mat:mat.clone()}));
+    world.push(Box::new(shape::Sphere{center:vector::Vec3d::new(0.,2.,0.), radius:2., mat:mat.clone()}));
+    let light = Arc::new(material::DiffuseLight::new(color::Spectrum::new(4.,4.,4.)));
+    world.push(Box::new(shape::Sphere{center:vector::Vec3d::new(0.,7.,0.), radius:2., mat:light}));
+    shape::Objects::new(world)
+}
+
+// Seen from (278, 278, -800) looking at (278, 278, 0) with a 40 degree field of view and a black background.
+fn cornell_box() -> shape::Objects {
+    let mut world: Vec<Box<dyn shape::Shape>> = Vec::new();
+    let red = Arc::new(material::Lambertian::new(color::Spectrum::new(0.65, 0.05, 0.05)));
+    let white = Arc::new(material::Lambertian::new(color::Spectrum::new(0.73, 0.73, 0.73)));
+    let green = Arc::new(material::Lambertian::new(color::Spectrum::new(0.12, 0.45, 0.15)));
+    let light = Arc::new(material::DiffuseLight::new(color::Spectrum::new(15., 15., 15.)));
+    world.push(Box::new(shape::YZRect{y0:0., y1:555., z0:0., z1:555., k:555., mat:green}));
+    world.push(Box::new(shape::YZRect{y0:0., y1:555., z0:0., z1:555., k:0., mat:red}));
+    world.push(Box::new(shape::XZRect{x0:213., x1:343., z0:227., z1:332., k:554., mat:light}));
+    world.push(Box::new(shape::XZRect{x0:0., x1:555., z0:0., z1:555., k:0., mat:white.clone()}));
+    world.push(Box::new(shape::XZRect{x0:0., x1:555., z0:0., z1:555., k:555., mat:white.clone()}));
+    world.push(Box::new(shape::XYRect{x0:0., x1:555., y0:0., y1:555., k:555., mat:white.clone()}));
+    let tall = Arc::new(shape::Cuboid::new(Vec3d::zero(), Vec3d::new(165., 330., 165.), white.clone()));
+    let tall_place = transform::Transform::rotate(Vec3d::new(0., 1., 0.), 15.).then(transform::Transform::translate(Vec3d::new(265., 0., 295.)));
+    world.push(Box::new(transform::Instance::new(tall, tall_place)));
+    let short = Arc::new(shape::Cuboid::new(Vec3d::zero(), Vec3d::new(165., 165., 165.), white));
+    let short_place = transform::Transform::rotate(Vec3d::new(0., 1., 0.), -18.).then(transform::Transform::translate(Vec3d::new(130., 0., 65.)));
+    world.push(Box::new(transform::Instance::new(short, short_place)));
+    shape::Objects::new(world)
+}
+
+// The Cornell box with its two boxes turned into smoke and fog.
+fn cornell_smoke() -> shape::Objects {
+    let mut world: Vec<Box<dyn shape::Shape>> = Vec::new();
+    let red = Arc::new(material::Lambertian::new(color::Spectrum::new(0.65, 0.05, 0.05)));
+    let white = Arc::new(material::Lambertian::new(color::Spectrum::new(0.73, 0.73, 0.73)));
+    let green = Arc::new(material::Lambertian::new(color::Spectrum::new(0.12, 0.45, 0.15)));
+    let light = Arc::new(material::DiffuseLight::new(color::Spectrum::new(7., 7., 7.)));
+    world.push(Box::new(shape::YZRect{y0:0., y1:555., z0:0., z1:555., k:555., mat:green}));
+    world.push(Box::new(shape::YZRect{y0:0., y1:555., z0:0., z1:555., k:0., mat:red}));
+    world.push(Box::new(shape::XZRect{x0:113., x1:443., z0:127., z1:432., k:554., mat:light}));
+    world.push(Box::new(shape::XZRect{x0:0., x1:555., z0:0., z1:555., k:0., mat:white.clone()}));
+    world.push(Box::new(shape::XZRect{x0:0., x1:555., z0:0., z1:555., k:555., mat:white.clone()}));
+    world.push(Box::new(shape::XYRect{x0:0., x1:555., y0:0., y1:555., k:555., mat:white.clone()}));
+    let tall = Arc::new(shape::Cuboid::new(Vec3d::zero(), Vec3d::new(165., 330., 165.), white.clone()));
+    let tall_place = transform::Transform::rotate(Vec3d::new(0., 1., 0.), 15.).then(transform::Transform::translate(Vec3d::new(265., 0., 295.)));
+    let tall = Arc::new(transform::Instance::new(tall, tall_place));
+    world.push(Box::new(medium::ConstantMedium::new(tall, 0.01, color::Spectrum::black()).unwrap()));
+    let short = Arc::new(shape::Cuboid::new(Vec3d::zero(), Vec3d::new(165., 165., 165.), white));
+    let short_place = transform::Transform::rotate(Vec3d::new(0., 1., 0.), -18.).then(transform::Transform::translate(Vec3d::new(130., 0., 65.)));
+    let short = Arc::new(transform::Instance::new(short, short_place));
+    world.push(Box::new(medium::ConstantMedium::new(short, 0.01, color::Spectrum::white()).unwrap()));
+    shape::Objects::new(world)
+}
+
 /*
 fn main() {
     let mut img = Image::new(256, 256);
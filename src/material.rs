@@ -1,32 +1,84 @@
 use crate::vector::Vec3d;
 use crate::ray::Ray;
-use crate::color::RGB;
+use crate::color::Spectrum;
 use crate::shape::Hit;
 use crate::texture::Texture;
 use crate::texture::SolidTexture;
-extern crate rand;
-use rand::Rng;
+use crate::sampler::Sampler;
+use crate::pdf::{CosinePdf, Pdf, PhongPdf, UniformSpherePdf};
+use std::f64::consts::PI;
 
+// `f` is the BSDF times the cosine at the surface (or the phase function in a medium) for
+// the sampled direction, and `pdf` its solid angle density, so a path is weighted by f/pdf.
+// Specular scattering has no density: `f` is then the attenuation and `pdf` is 1.
 #[derive(Clone, Debug)]
 pub struct Scatter {
     pub s: bool,
     pub r: Ray,
-    pub a: RGB,
+    pub f: Spectrum,
+    pub pdf: f64,
+    pub specular: bool,
 }
 
-pub trait Material {
-    fn scatter(&self, r_in: &Ray, hit: &Hit) -> Scatter;
+impl Scatter {
+    pub fn none(r_in: &Ray) -> Scatter {
+        let r = Ray::new(Vec3d::zero(), Vec3d::zero(), r_in.t);
+        Scatter{s:false, r, f:Spectrum::black(), pdf:0., specular:false}
+    }
+    pub fn specular(r: Ray, attenuation: Spectrum) -> Scatter {
+        Scatter{s:true, r, f:attenuation, pdf:1., specular:true}
+    }
+    pub fn weight(&self) -> Spectrum {
+        if self.pdf > 0. {self.f/self.pdf} else {Spectrum::black()}
+    }
+}
+
+pub trait Material: Send + Sync {
+    fn scatter(&self, r_in: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Scatter;
+    fn emitted(&self, _u: f64, _v: f64, _p: Vec3d) -> Spectrum {
+        Spectrum::black()
+    }
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    // `f` as in `Scatter`, for a direction chosen by someone else. Zero for specular materials.
+    fn eval(&self, _r_in: &Ray, _hit: &Hit, _dir: Vec3d) -> Spectrum {
+        Spectrum::black()
+    }
+    // The density `scatter` would have picked `dir` with. Zero for specular materials.
+    fn pdf(&self, _r_in: &Ray, _hit: &Hit, _dir: Vec3d) -> f64 {
+        0.
+    }
+}
+
+// What a material's `scatter` samples at one hit, as a `Pdf` that can be mixed with others.
+pub struct BsdfPdf<'a> {
+    pub r_in: &'a Ray,
+    pub hit: &'a Hit,
+}
+
+impl<'a> BsdfPdf<'a> {
+    pub fn new(r_in: &'a Ray, hit: &'a Hit) -> BsdfPdf<'a> {
+        BsdfPdf{r_in, hit}
+    }
+}
+
+impl<'a> Pdf for BsdfPdf<'a> {
+    fn value(&self, dir: Vec3d) -> f64 {
+        self.hit.mat.pdf(self.r_in, self.hit, dir)
+    }
+    // The zero vector when the material absorbed the ray.
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        self.hit.mat.scatter(self.r_in, self.hit, sampler).r.dir
+    }
 }
 
 pub struct Nothing {
 }
 
 impl Material for Nothing {
-    fn scatter(&self, r_in: &Ray, _: &Hit) -> Scatter {
-        let s = false;
-        let r = Ray::new(Vec3d::zero(), Vec3d::zero(), r_in.t);
-        let a = RGB::black();
-        Scatter{s, r, a}
+    fn scatter(&self, r_in: &Ray, _: &Hit, _: &mut dyn Sampler) -> Scatter {
+        Scatter::none(r_in)
     }
 }
 
@@ -35,38 +87,81 @@ pub struct Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, r_in: &Ray, hit: &Hit) -> Scatter {
-        let s = true;
-        let mut dir = hit.n + Vec3d::rand_in_unit_sphere().norm();
-        if dir.near_zero() {
-            dir = hit.n;
+    fn scatter(&self, r_in: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Scatter {
+        let dir = CosinePdf::new(hit.n).generate(sampler);
+        let pdf = self.pdf(r_in, hit, dir);
+        if pdf <= 0. {
+            return Scatter::none(r_in);
         }
         let r = Ray::new(hit.p, dir, r_in.t);
-        let a = self.albedo.value(hit.u, hit.v, hit.p);
-        Scatter{s, r, a}
-    }    
+        let f = self.eval(r_in, hit, dir);
+        Scatter{s:true, r, f, pdf, specular:false}
+    }
+    fn eval(&self, _r_in: &Ray, hit: &Hit, dir: Vec3d) -> Spectrum {
+        let cosine = Vec3d::dot(hit.n, dir.norm());
+        if cosine <= 0. {
+            return Spectrum::black();
+        }
+        self.albedo.value(hit.u, hit.v, hit.p)*(cosine/PI)
+    }
+    fn pdf(&self, _r_in: &Ray, hit: &Hit, dir: Vec3d) -> f64 {
+        CosinePdf::new(hit.n).value(dir)
+    }
 }
 
 impl Lambertian {
-    pub fn new(color:RGB) -> Lambertian {
+    pub fn new(color:Spectrum) -> Lambertian {
         let albedo = SolidTexture{color};
         Lambertian{albedo:Box::new(albedo)}
     }
 }
 
 pub struct Metal {
-    pub albedo: RGB,
+    pub albedo: Spectrum,
     pub fuzz: f64,
 }
 
+// A mirror when `fuzz` is 0. Otherwise the reflection is spread over a Phong lobe around the
+// mirror direction, with `f` chosen so a sampled direction carries the albedo as before.
+// Nudging the mirror direction by `fuzz` times a point in the unit ball, as `Metal` used to,
+// gives directions whose mean cosine to the mirror direction is 1 - fuzz^2/5 (for fuzz up to 1).
+// The lobe's exponent is picked to match, so scenes look as they did.
 impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, hit: &Hit) -> Scatter {
-        let reflected = Vec3d::reflect(r_in.dir, hit.n);
-        let s = Vec3d::dot(reflected, hit.n) > 0.;
-        let r = Ray::new(hit.p, reflected + self.fuzz*Vec3d::rand_in_unit_sphere(), r_in.t);
-        let a = self.albedo;
-        Scatter{s, r, a}
-    }  
+    fn scatter(&self, r_in: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Scatter {
+        if self.fuzz <= 0. {
+            let reflected = Vec3d::reflect(r_in.dir, hit.n);
+            if Vec3d::dot(reflected, hit.n) <= 0. {
+                return Scatter::none(r_in);
+            }
+            return Scatter::specular(Ray::new(hit.p, reflected, r_in.t), self.albedo);
+        }
+        let dir = self.lobe(r_in, hit).generate(sampler);
+        let pdf = self.pdf(r_in, hit, dir);
+        if pdf <= 0. {
+            return Scatter::none(r_in);
+        }
+        let r = Ray::new(hit.p, dir, r_in.t);
+        Scatter{s:true, r, f:self.albedo*pdf, pdf, specular:false}
+    }
+    fn eval(&self, r_in: &Ray, hit: &Hit, dir: Vec3d) -> Spectrum {
+        self.albedo*self.pdf(r_in, hit, dir)
+    }
+    fn pdf(&self, r_in: &Ray, hit: &Hit, dir: Vec3d) -> f64 {
+        if self.fuzz <= 0. || Vec3d::dot(dir, hit.n) <= 0. {
+            return 0.;
+        }
+        self.lobe(r_in, hit).value(dir)
+    }
+}
+
+impl Metal {
+    // Phong exponent with the same mean cosine as the old fuzz: (n+1)/(n+2) = 1 - fuzz^2/5.
+    pub fn exponent(&self) -> f64 {
+        (5./(self.fuzz*self.fuzz) - 2.).max(0.)
+    }
+    fn lobe(&self, r_in: &Ray, hit: &Hit) -> PhongPdf {
+        PhongPdf::new(Vec3d::reflect(r_in.dir.norm(), hit.n), self.exponent())
+    }
 }
 
 pub struct Dielectric {
@@ -74,21 +169,21 @@ pub struct Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, hit: &Hit) -> Scatter {
-        let a = Vec3d::one().to_rgb();
+    #[allow(clippy::needless_late_init)]
+    fn scatter(&self, r_in: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Scatter {
         let rf_ratio = if hit.f {1./self.ir} else {self.ir};
         let cos_theta = f64::min(Vec3d::dot(-r_in.dir.norm(), hit.n.norm()), 1.);
         let sin_theta = (1. - cos_theta*cos_theta).sqrt();
         let not_refract  = rf_ratio * sin_theta > 1.;
         let dir: Vec3d;
-        if not_refract || Dielectric::reflectance(cos_theta, rf_ratio) > rand::thread_rng().gen_range(0., 1.){
+        if not_refract || Dielectric::reflectance(cos_theta, rf_ratio) > sampler.get_1d() {
             dir = Vec3d::reflect(r_in.dir, hit.n);
         }
         else {
             dir = Vec3d::refract(r_in.dir, hit.n, rf_ratio);
         }
         let r = Ray::new(hit.p, dir, r_in.t);
-        Scatter{s:true, r, a}
+        Scatter::specular(r, Spectrum::white())
     }
 }
 
@@ -99,4 +194,92 @@ impl Dielectric {
         let r0 = r0 * r0;
         r0 + (1.-r0)*((1.-cosine).powi(5))
     }
-}
\ No newline at end of file
+}
+
+pub struct DiffuseLight {
+    pub emit: Box<dyn Texture>,
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, r_in: &Ray, _: &Hit, _: &mut dyn Sampler) -> Scatter {
+        Scatter::none(r_in)
+    }
+    fn emitted(&self, u: f64, v: f64, p: Vec3d) -> Spectrum {
+        self.emit.value(u, v, p)
+    }
+    fn is_emissive(&self) -> bool {
+        true
+    }
+}
+
+impl DiffuseLight {
+    pub fn new(color:Spectrum) -> DiffuseLight {
+        let emit = SolidTexture{color};
+        DiffuseLight{emit:Box::new(emit)}
+    }
+}
+
+// Phase function of a participating medium: scatters evenly in every direction.
+pub struct Isotropic {
+    pub albedo: Box<dyn Texture>,
+}
+
+impl Material for Isotropic {
+    fn scatter(&self, r_in: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Scatter {
+        let dir = UniformSpherePdf{}.generate(sampler);
+        let r = Ray::new(hit.p, dir, r_in.t);
+        let f = self.eval(r_in, hit, dir);
+        let pdf = self.pdf(r_in, hit, dir);
+        Scatter{s:true, r, f, pdf, specular:false}
+    }
+    fn eval(&self, _r_in: &Ray, hit: &Hit, _dir: Vec3d) -> Spectrum {
+        self.albedo.value(hit.u, hit.v, hit.p)/(4.*PI)
+    }
+    fn pdf(&self, _r_in: &Ray, _hit: &Hit, dir: Vec3d) -> f64 {
+        UniformSpherePdf{}.value(dir)
+    }
+}
+
+impl Isotropic {
+    pub fn new(color:Spectrum) -> Isotropic {
+        let albedo = SolidTexture{color};
+        Isotropic{albedo:Box::new(albedo)}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+    use crate::texture::CheckerTexture;
+
+    #[test]
+    fn fuzz_keeps_its_old_spread() {
+        let mut sampler = IndependentSampler::new(3);
+        let n = 100000;
+        for &fuzz in &[0.1, 0.3, 0.7, 1.] {
+            let metal = Metal{albedo:Spectrum::white(), fuzz};
+            let lobe = (metal.exponent() + 1.)/(metal.exponent() + 2.);
+            // The old scatter: the mirror direction (here +z) plus fuzz times a point in the unit ball.
+            let old = (0..n).map(|_| (Vec3d::new(0., 0., 1.) + fuzz*Vec3d::rand_in_unit_sphere(&mut sampler)).norm().z).sum::<f64>()/n as f64;
+            assert!((lobe - old).abs() < 2e-3, "fuzz {}: lobe {} old {}", fuzz, lobe, old);
+        }
+    }
+
+    #[test]
+    fn lights_emit_their_texture_and_scatter_nothing() {
+        let bright = Spectrum::new(15., 15., 15.);
+        let light = DiffuseLight{emit:Box::new(CheckerTexture::new(bright, Spectrum::black()))};
+        assert!(light.is_emissive());
+        // The checker's sines are negative here and positive there.
+        let (odd, even) = (Vec3d::new(-0.1, 0.1, 0.1), Vec3d::new(0.1, 0.1, 0.1));
+        assert_eq!(light.emitted(0., 0., odd), bright);
+        assert!(light.emitted(0., 0., even).is_black());
+        let r = Ray::new(Vec3d::zero(), Vec3d::new(0., 0., -1.), 0.);
+        let hit = Hit{p:Vec3d::new(0., 0., -1.), n:Vec3d::new(0., 0., 1.), t:1., h:true, f:true, ..Hit::miss()};
+        assert!(!light.scatter(&r, &hit, &mut IndependentSampler::new(1)).s);
+        let lambertian = Lambertian::new(Spectrum::white());
+        assert!(!lambertian.is_emissive());
+        assert!(lambertian.emitted(0., 0., odd).is_black());
+    }
+}
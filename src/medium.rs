@@ -0,0 +1,102 @@
+use crate::vector::Vec3d;
+use crate::ray::Ray;
+use crate::color::Spectrum;
+use crate::material::{Isotropic, Material};
+use crate::aabb::AABB;
+use crate::shape::{Hit, Shape};
+use crate::sampler;
+use std::sync::Arc;
+
+// A volume of constant density filling a closed, convex boundary shape.
+pub struct ConstantMedium {
+    pub boundary: Arc<dyn Shape>,
+    pub neg_inv_density: f64,
+    pub phase: Arc<dyn Material>,
+}
+
+impl ConstantMedium {
+    // `None` unless `density` is positive and finite.
+    pub fn new(boundary: Arc<dyn Shape>, density: f64, color: Spectrum) -> Option<ConstantMedium> {
+        ConstantMedium::with_phase(boundary, density, Arc::new(Isotropic::new(color)))
+    }
+    pub fn with_phase(boundary: Arc<dyn Shape>, density: f64, phase: Arc<dyn Material>) -> Option<ConstantMedium> {
+        if !(density > 0. && density.is_finite()) {
+            return None;
+        }
+        Some(ConstantMedium{boundary, neg_inv_density:-1./density, phase})
+    }
+}
+
+impl Shape for ConstantMedium {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        let h1 = self.boundary.hit(r, -f64::INFINITY, f64::INFINITY);
+        if !h1.h {
+            return Hit::miss();
+        }
+        let h2 = self.boundary.hit(r, h1.t + 0.0001, f64::INFINITY);
+        if !h2.h {
+            return Hit::miss();
+        }
+        let t1 = f64::max(f64::max(h1.t, tmin), 0.);
+        let t2 = f64::min(h2.t, tmax);
+        if t1 >= t2 {
+            return Hit::miss();
+        }
+        let ray_len = r.dir.len();
+        let dist_inside = (t2 - t1)*ray_len;
+        // The ray's key comes from the sampler. Hashing it with where the ray enters keeps media
+        // along the same ray independent of each other.
+        let u = sampler::hash_uniform(&[r.key, r.ori.x.to_bits(), r.ori.y.to_bits(), r.ori.z.to_bits(),
+                                        r.dir.x.to_bits(), r.dir.y.to_bits(), r.dir.z.to_bits(), t1.to_bits()]);
+        // Exponential free flight; 1 - u keeps the logarithm finite.
+        let hit_dist = self.neg_inv_density*(1. - u).ln();
+        if hit_dist > dist_inside {
+            return Hit::miss();
+        }
+        let t = t1 + hit_dist/ray_len;
+        let p = r.at(t);
+        // Normal and facing mean nothing inside a volume, these are arbitrary.
+        Hit{t, p, n:Vec3d::new(1., 0., 0.), ng:Vec3d::new(1., 0., 0.), h:true, u:0., v:0., f:true, mat:Arc::clone(&self.phase)}
+    }
+    fn bound(&self, t0: f64, t1: f64) -> AABB {
+        self.boundary.bound(t0, t1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::Sphere;
+    use crate::material::Lambertian;
+
+    fn ball(density: f64) -> Option<ConstantMedium> {
+        let mat = Arc::new(Lambertian::new(Spectrum::white()));
+        let boundary = Arc::new(Sphere{center:Vec3d::zero(), radius:1000., mat});
+        ConstantMedium::new(boundary, density, Spectrum::white())
+    }
+
+    #[test]
+    fn density_must_be_positive_and_finite() {
+        assert!(ball(0.).is_none());
+        assert!(ball(-1.).is_none());
+        assert!(ball(f64::NAN).is_none());
+        assert!(ball(f64::INFINITY).is_none());
+        assert!(ball(0.5).is_some());
+    }
+
+    #[test]
+    fn free_flights_follow_the_ray_key() {
+        let medium = ball(0.5).unwrap();
+        let r = Ray::new(Vec3d::zero(), Vec3d::new(0., 0., 1.), 0.);
+        let n = 20000;
+        let depths: Vec<f64> = (0..n).map(|k| {
+            let hit = medium.hit(&r.clone().with_key(k + 1), 0., f64::INFINITY);
+            assert!(hit.h);
+            hit.t
+        }).collect();
+        // Exponential with a mean of 1/density.
+        let mean = depths.iter().sum::<f64>()/n as f64;
+        assert!((mean - 2.).abs() < 0.1, "mean free path {}", mean);
+        assert_eq!(medium.hit(&r.clone().with_key(7), 0., f64::INFINITY).t, depths[6]);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::vector::Vec3d;
+use crate::ray::Ray;
+use crate::material::Material;
+use crate::aabb::AABB;
+use crate::shape::{area_pdf, Hit, Shape};
+use crate::bvh::{BvhBuilder, BvhNode};
+use crate::sampler::Sampler;
+use std::fmt;
+use std::sync::Arc;
+
+// Triangles have no thickness along an axis they are parallel to, so their boxes are padded like the rects.
+const TRI_PAD: f64 = 0.0001;
+
+#[derive(Clone)]
+pub struct Triangle {
+    pub p: [Vec3d; 3],
+    pub n: Option<[Vec3d; 3]>,
+    pub uv: Option<[(f64, f64); 3]>,
+    pub mat: Arc<dyn Material>,
+}
+
+impl Triangle {
+    pub fn new(p0: Vec3d, p1: Vec3d, p2: Vec3d, mat: Arc<dyn Material>) -> Triangle {
+        Triangle{p:[p0, p1, p2], n:None, uv:None, mat}
+    }
+}
+
+impl Shape for Triangle {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        match intersect(&self.p, r, tmin, tmax) {
+            Some((t, b1, b2)) => fill_hit(r, t, b1, b2, &self.p, self.n.as_ref(), self.uv.as_ref(), &self.mat),
+            None => Hit::miss(),
+        }
+    }
+    fn bound(&self, _: f64, _: f64) -> AABB {
+        triangle_bound(&self.p)
+    }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        triangle_pdf(&self.p, o, v)
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        triangle_point(&self.p, sampler) - o
+    }
+}
+
+// Vertex buffers shared by every face of a mesh. `normals` and `uvs` are either empty
+// or hold one entry per position, and `indices` index all three buffers alike.
+pub struct MeshData {
+    pub positions: Vec<Vec3d>,
+    pub normals: Vec<Vec3d>,
+    pub uvs: Vec<(f64, f64)>,
+    pub indices: Vec<[usize; 3]>,
+    pub mat: Arc<dyn Material>,
+}
+
+// One face of a mesh, looked up through the shared buffers instead of holding its own copy.
+pub struct MeshTriangle {
+    pub data: Arc<MeshData>,
+    pub face: usize,
+}
+
+impl MeshTriangle {
+    fn vertices(&self) -> [Vec3d; 3] {
+        let [i0, i1, i2] = self.data.indices[self.face];
+        [self.data.positions[i0], self.data.positions[i1], self.data.positions[i2]]
+    }
+}
+
+impl Shape for MeshTriangle {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        let p = self.vertices();
+        let (t, b1, b2) = match intersect(&p, r, tmin, tmax) {
+            Some(x) => x,
+            None => return Hit::miss(),
+        };
+        let [i0, i1, i2] = self.data.indices[self.face];
+        let n = if self.data.normals.is_empty() {None} else {
+            Some([self.data.normals[i0], self.data.normals[i1], self.data.normals[i2]])
+        };
+        let uv = if self.data.uvs.is_empty() {None} else {
+            Some([self.data.uvs[i0], self.data.uvs[i1], self.data.uvs[i2]])
+        };
+        fill_hit(r, t, b1, b2, &p, n.as_ref(), uv.as_ref(), &self.data.mat)
+    }
+    fn bound(&self, _: f64, _: f64) -> AABB {
+        triangle_bound(&self.vertices())
+    }
+    fn is_light(&self) -> bool {
+        self.data.mat.is_emissive()
+    }
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        triangle_pdf(&self.vertices(), o, v)
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        triangle_point(&self.vertices(), sampler) - o
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeshError {
+    IndexOutOfRange{face: usize, index: usize, positions: usize},
+    Normals{expected: usize, found: usize},
+    Uvs{expected: usize, found: usize},
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::IndexOutOfRange{face, index, positions} => write!(f, "face {} uses vertex {}, but the mesh has {} positions", face, index, positions),
+            MeshError::Normals{expected, found} => write!(f, "expected no normals or {}, found {}", expected, found),
+            MeshError::Uvs{expected, found} => write!(f, "expected no texcoords or {}, found {}", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+pub struct TriangleMesh {
+    pub data: Arc<MeshData>,
+    pub bvh: BvhNode,
+    // Running sum of face areas, for picking a face in proportion to its area.
+    pub area_cdf: Vec<f64>,
+}
+
+impl TriangleMesh {
+    // Checks the buffers against each other up front, so a bad index cannot panic mid-render.
+    pub fn new(positions: Vec<Vec3d>, normals: Vec<Vec3d>, uvs: Vec<(f64, f64)>, indices: Vec<[usize; 3]>, mat: Arc<dyn Material>) -> Result<TriangleMesh, MeshError> {
+        if !normals.is_empty() && normals.len() != positions.len() {
+            return Err(MeshError::Normals{expected:positions.len(), found:normals.len()});
+        }
+        if !uvs.is_empty() && uvs.len() != positions.len() {
+            return Err(MeshError::Uvs{expected:positions.len(), found:uvs.len()});
+        }
+        for (face, tri) in indices.iter().enumerate() {
+            if let Some(&index) = tri.iter().find(|&&i| i >= positions.len()) {
+                return Err(MeshError::IndexOutOfRange{face, index, positions:positions.len()});
+            }
+        }
+        let data = Arc::new(MeshData{positions, normals, uvs, indices, mat});
+        let faces: Vec<Arc<dyn Shape>> = (0..data.indices.len()).map(|face| {
+            Arc::new(MeshTriangle{data:Arc::clone(&data), face}) as Arc<dyn Shape>
+        }).collect();
+        let bvh = BvhBuilder::default().build(faces, 0., 0.);
+        let mut total = 0.;
+        let area_cdf = data.indices.iter().map(|&[i0, i1, i2]| {
+            total += triangle_area(&[data.positions[i0], data.positions[i1], data.positions[i2]]);
+            total
+        }).collect();
+        Ok(TriangleMesh{data, bvh, area_cdf})
+    }
+    fn face(&self, k: usize) -> [Vec3d; 3] {
+        let [i0, i1, i2] = self.data.indices[k];
+        [self.data.positions[i0], self.data.positions[i1], self.data.positions[i2]]
+    }
+    pub fn len(&self) -> usize {
+        self.data.indices.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.indices.is_empty()
+    }
+}
+
+impl Shape for TriangleMesh {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        self.bvh.hit(r, tmin, tmax)
+    }
+    fn bound(&self, t0: f64, t1: f64) -> AABB {
+        self.bvh.bound(t0, t1)
+    }
+    fn is_light(&self) -> bool {
+        self.data.mat.is_emissive() && self.area_cdf.last().is_some_and(|&a| a > 0.)
+    }
+    // Points are uniform over the whole surface, so the density only needs the face's own normal
+    // where the ray lands, whatever the vertex normals say.
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        match self.area_cdf.last() {
+            Some(&total) if total > 0. => area_pdf(self, o, v, total),
+            _ => 0.,
+        }
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        let total = match self.area_cdf.last() {
+            Some(&total) => total,
+            None => return Vec3d::new(1., 0., 0.),
+        };
+        let x = sampler.get_1d()*total;
+        let k = self.area_cdf.partition_point(|&a| a <= x).min(self.area_cdf.len() - 1);
+        triangle_point(&self.face(k), sampler) - o
+    }
+}
+
+// Moller-Trumbore. Returns the ray parameter and the barycentric weights of p1 and p2.
+fn intersect(p: &[Vec3d; 3], r: &Ray, tmin: f64, tmax: f64) -> Option<(f64, f64, f64)> {
+    let e1 = p[1] - p[0];
+    let e2 = p[2] - p[0];
+    let pv = Vec3d::cross(r.dir, e2);
+    let det = Vec3d::dot(e1, pv);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1./det;
+    let tv = r.ori - p[0];
+    let b1 = Vec3d::dot(tv, pv)*inv_det;
+    if !(0. ..=1.).contains(&b1) {
+        return None;
+    }
+    let qv = Vec3d::cross(tv, e1);
+    let b2 = Vec3d::dot(r.dir, qv)*inv_det;
+    if b2 < 0. || b1 + b2 > 1. {
+        return None;
+    }
+    let t = Vec3d::dot(e2, qv)*inv_det;
+    if t < tmin || t > tmax {
+        return None;
+    }
+    Some((t, b1, b2))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fill_hit(r: &Ray, t: f64, b1: f64, b2: f64, p: &[Vec3d; 3], n: Option<&[Vec3d; 3]>, uv: Option<&[(f64, f64); 3]>, mat: &Arc<dyn Material>) -> Hit {
+    let b0 = 1. - b1 - b2;
+    let mut out_norm = Vec3d::cross(p[1] - p[0], p[2] - p[0]).norm();
+    let mut shading = out_norm;
+    if let Some(n) = n {
+        let ns = b0*n[0] + b1*n[1] + b2*n[2];
+        if ns.lensq() > 0. {
+            shading = ns.norm();
+            // The winding order may disagree with the vertex normals; the normals win.
+            if Vec3d::dot(shading, out_norm) < 0. {
+                out_norm = -out_norm;
+            }
+        }
+    }
+    let (u, v) = match uv {
+        Some(uv) => (b0*uv[0].0 + b1*uv[1].0 + b2*uv[2].0, b0*uv[0].1 + b1*uv[1].1 + b2*uv[2].1),
+        None => (b1, b2),
+    };
+    let h = true;
+    let f = Hit::set_face(r, out_norm);
+    let n = Hit::set_norm(f, shading);
+    let ng = Hit::set_norm(f, out_norm);
+    Hit{t, p:r.at(t), n, ng, h, u, v, f, mat:Arc::clone(mat)}
+}
+
+fn triangle_area(p: &[Vec3d; 3]) -> f64 {
+    0.5*Vec3d::cross(p[1] - p[0], p[2] - p[0]).len()
+}
+
+// Uniform over the triangle's area.
+fn triangle_point(p: &[Vec3d; 3], sampler: &mut dyn Sampler) -> Vec3d {
+    let (u1, u2) = sampler.get_2d();
+    let s = u1.sqrt();
+    let (b1, b2) = (s*(1. - u2), s*u2);
+    (1. - b1 - b2)*p[0] + b1*p[1] + b2*p[2]
+}
+
+// Solid angle density of `triangle_point`, seen from `o` along `v`.
+fn triangle_pdf(p: &[Vec3d; 3], o: Vec3d, v: Vec3d) -> f64 {
+    let r = Ray::new(o, v, 0.);
+    let t = match intersect(p, &r, 0.0001, f64::INFINITY) {
+        Some((t, _, _)) => t,
+        None => return 0.,
+    };
+    let n = Vec3d::cross(p[1] - p[0], p[2] - p[0]).norm();
+    let cosine = Vec3d::dot(v.norm(), n).abs();
+    if cosine <= 0. {
+        return 0.;
+    }
+    t*t*v.lensq()/(cosine*triangle_area(p))
+}
+
+fn triangle_bound(p: &[Vec3d; 3]) -> AABB {
+    let pad = TRI_PAD*Vec3d::one();
+    let min = Vec3d::comp_min(p[0], Vec3d::comp_min(p[1], p[2])) - pad;
+    let max = Vec3d::comp_max(p[0], Vec3d::comp_max(p[1], p[2])) + pad;
+    AABB::new(min, max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::DiffuseLight;
+    use crate::color::Spectrum;
+
+    #[test]
+    fn light_pdf_ignores_vertex_normals() {
+        let positions = vec![Vec3d::new(-1., -1., 2.), Vec3d::new(1., -1., 2.), Vec3d::new(1., 1., 2.), Vec3d::new(-1., 1., 2.)];
+        // Tilted well away from the face, as smooth shading of a curved surface would have them.
+        let normals = vec![Vec3d::new(0.8, 0., -1.), Vec3d::new(-0.8, 0., -1.), Vec3d::new(0., 0.8, -1.), Vec3d::new(0., -0.8, -1.)];
+        let mat = Arc::new(DiffuseLight::new(Spectrum::white()));
+        let mesh = TriangleMesh::new(positions, normals, Vec::new(), vec![[0, 1, 2], [0, 2, 3]], mat).unwrap();
+        assert!(mesh.is_light());
+        let o = Vec3d::new(0.3, -0.2, 0.);
+        for &(x, y) in &[(0., 0.), (0.5, 0.5), (-0.7, 0.2), (0.9, -0.9)] {
+            let v = Vec3d::new(x, y, 2.) - o;
+            // Uniform over an area of 4, seen at distance |v| with the face's normal along z.
+            let cosine = v.z/v.len();
+            let expected = v.lensq()/(cosine*4.);
+            let pdf = mesh.pdf_value(o, v);
+            assert!((pdf - expected).abs() < 1e-9*expected, "pdf {} instead of {}", pdf, expected);
+        }
+    }
+
+    #[test]
+    fn bad_buffers_are_rejected() {
+        let mat: Arc<dyn Material> = Arc::new(DiffuseLight::new(Spectrum::white()));
+        let p = vec![Vec3d::zero(), Vec3d::new(1., 0., 0.), Vec3d::new(0., 1., 0.)];
+        assert_eq!(TriangleMesh::new(p.clone(), Vec::new(), Vec::new(), vec![[0, 1, 3]], mat.clone()).err(),
+                   Some(MeshError::IndexOutOfRange{face:0, index:3, positions:3}));
+        assert_eq!(TriangleMesh::new(p.clone(), vec![Vec3d::zero()], Vec::new(), vec![[0, 1, 2]], mat.clone()).err(),
+                   Some(MeshError::Normals{expected:3, found:1}));
+        assert_eq!(TriangleMesh::new(p, Vec::new(), vec![(0., 0.); 2], vec![[0, 1, 2]], mat).err(),
+                   Some(MeshError::Uvs{expected:3, found:2}));
+    }
+}
@@ -0,0 +1,444 @@
+use crate::vector::Vec3d;
+use crate::color::Spectrum;
+use crate::material;
+use crate::material::Material;
+use crate::texture::{ImageTexture, SolidTexture, Texture};
+use crate::mesh::{MeshError, TriangleMesh};
+use crate::shape::{Objects, Shape};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io{path: PathBuf, source: std::io::Error},
+    Parse{path: PathBuf, line: usize, msg: String},
+    Texture{path: PathBuf, source: bmp::BmpError},
+    Mesh{path: PathBuf, line: usize, source: MeshError},
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io{path, source} => write!(f, "{}: {}", path.display(), source),
+            ObjError::Parse{path, line, msg} => write!(f, "{}:{}: {}", path.display(), line, msg),
+            ObjError::Texture{path, source} => write!(f, "{}: {}", path.display(), source),
+            ObjError::Mesh{path, line, source} => write!(f, "{}:{}: {}", path.display(), line, source),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io{source, ..} => Some(source),
+            ObjError::Parse{..} => None,
+            ObjError::Texture{source, ..} => Some(source),
+            ObjError::Mesh{source, ..} => Some(source),
+        }
+    }
+}
+
+// One mesh per run of faces sharing an object/group name and a material.
+pub struct ObjMesh {
+    pub name: String,
+    pub material: Option<String>,
+    pub mesh: TriangleMesh,
+}
+
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Objects, ObjError> {
+    let meshes = load_obj_meshes(path)?;
+    let object = meshes.into_iter().map(|m| Box::new(m.mesh) as Box<dyn Shape>).collect();
+    Ok(Objects::new(object))
+}
+
+pub fn load_obj_meshes<P: AsRef<Path>>(path: P) -> Result<Vec<ObjMesh>, ObjError> {
+    let path = path.as_ref();
+    let text = read(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions: Vec<Vec3d> = Vec::new();
+    let mut normals: Vec<Vec3d> = Vec::new();
+    let mut uvs: Vec<(f64, f64)> = Vec::new();
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    let mut groups: Vec<Group> = Vec::new();
+    let mut name = String::new();
+    let mut mtl: Option<String> = None;
+
+    for (k, line) in text.lines().enumerate() {
+        let err = |msg: String| ObjError::Parse{path:path.to_path_buf(), line:k + 1, msg};
+        let mut words = statement(line);
+        let keyword = match words.next() {
+            Some(w) => w,
+            None => continue,
+        };
+        let args: Vec<&str> = words.collect();
+        match keyword {
+            "v" => positions.push(parse_vec(&args, 3).map_err(err)?),
+            "vn" => normals.push(parse_vec(&args, 3).map_err(err)?),
+            "vt" => {
+                let t = parse_floats(&args, 1).map_err(err)?;
+                uvs.push((t[0], if t.len() > 1 {t[1]} else {0.}));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(err(format!("face needs at least 3 vertices, found {}", args.len())));
+                }
+                let mut face = Vec::with_capacity(args.len());
+                for a in &args {
+                    face.push(parse_corner(a, positions.len(), uvs.len(), normals.len()).map_err(err)?);
+                }
+                let start = match groups.last() {
+                    Some(g) => g.name != name || g.material != mtl,
+                    None => true,
+                };
+                if start {
+                    groups.push(Group::new(name.clone(), mtl.clone(), k + 1));
+                }
+                let g = groups.last_mut().unwrap();
+                // Polygons are triangulated as a fan around their first corner.
+                for i in 1..face.len() - 1 {
+                    let tri = [g.vertex(face[0]), g.vertex(face[i]), g.vertex(face[i + 1])];
+                    g.indices.push(tri);
+                }
+            }
+            "o" | "g" => name = args.join(" "),
+            "usemtl" => {
+                let m = args.join(" ");
+                if m.is_empty() {
+                    return Err(err("usemtl without a material name".to_string()));
+                }
+                mtl = Some(m);
+            }
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err(err("mtllib without a file name".to_string()));
+                }
+                for file in &args {
+                    load_mtl(&dir.join(file), &mut materials)?;
+                }
+            }
+            // Smoothing groups, line elements and the like do not affect the triangles.
+            _ => {}
+        }
+    }
+
+    let default: Arc<dyn Material> = Arc::new(material::Lambertian::new(Spectrum::new(0.8, 0.8, 0.8)));
+    let mut meshes = Vec::with_capacity(groups.len());
+    for g in groups {
+        let mat = match &g.material {
+            Some(m) => match materials.get(m) {
+                Some(mat) => mat.clone(),
+                None => return Err(ObjError::Parse{path:path.to_path_buf(), line:g.line, msg:format!("material `{}` is not defined", m)}),
+            },
+            None => default.clone(),
+        };
+        let line = g.line;
+        meshes.push(g.finish(&positions, &uvs, &normals, mat).map_err(|source| ObjError::Mesh{path:path.to_path_buf(), line, source})?);
+    }
+    Ok(meshes)
+}
+
+struct Group {
+    name: String,
+    material: Option<String>,
+    // Line of the first face, for reporting an undefined material.
+    line: usize,
+    // Indices of position, texcoord and normal per unified vertex.
+    corners: Vec<(usize, Option<usize>, Option<usize>)>,
+    lookup: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+    indices: Vec<[usize; 3]>,
+}
+
+impl Group {
+    fn new(name: String, material: Option<String>, line: usize) -> Group {
+        Group{name, material, line, corners:Vec::new(), lookup:HashMap::new(), indices:Vec::new()}
+    }
+    fn vertex(&mut self, corner: (usize, Option<usize>, Option<usize>)) -> usize {
+        let corners = &mut self.corners;
+        *self.lookup.entry(corner).or_insert_with(|| {
+            corners.push(corner);
+            corners.len() - 1
+        })
+    }
+    fn finish(self, positions: &[Vec3d], uvs: &[(f64, f64)], normals: &[Vec3d], mat: Arc<dyn Material>) -> Result<ObjMesh, MeshError> {
+        let p = self.corners.iter().map(|c| positions[c.0]).collect();
+        // Texcoords and normals are only kept when every corner has one.
+        let t = if self.corners.iter().all(|c| c.1.is_some()) {
+            self.corners.iter().map(|c| uvs[c.1.unwrap()]).collect()
+        } else {Vec::new()};
+        let n = if self.corners.iter().all(|c| c.2.is_some()) {
+            self.corners.iter().map(|c| normals[c.2.unwrap()]).collect()
+        } else {Vec::new()};
+        let mesh = TriangleMesh::new(p, n, t, self.indices, mat)?;
+        Ok(ObjMesh{name:self.name, material:self.material, mesh})
+    }
+}
+
+#[derive(Default)]
+struct MtlDesc {
+    kd: Option<Spectrum>,
+    ks: Option<Spectrum>,
+    ke: Option<Spectrum>,
+    ns: Option<f64>,
+    ni: Option<f64>,
+    dissolve: Option<f64>,
+    illum: Option<u32>,
+    map_kd: Option<PathBuf>,
+}
+
+fn load_mtl(path: &Path, materials: &mut HashMap<String, Arc<dyn Material>>) -> Result<(), ObjError> {
+    let text = read(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut current: Option<(String, MtlDesc)> = None;
+    for (k, line) in text.lines().enumerate() {
+        let err = |msg: String| ObjError::Parse{path:path.to_path_buf(), line:k + 1, msg};
+        let mut words = statement(line);
+        let keyword = match words.next() {
+            Some(w) => w,
+            None => continue,
+        };
+        let args: Vec<&str> = words.collect();
+        if keyword == "newmtl" {
+            if let Some((name, desc)) = current.take() {
+                materials.insert(name, to_material(desc)?);
+            }
+            if args.is_empty() {
+                return Err(err("newmtl without a name".to_string()));
+            }
+            current = Some((args.join(" "), MtlDesc::default()));
+            continue;
+        }
+        let desc = match &mut current {
+            Some((_, d)) => d,
+            None => return Err(err(format!("`{}` before any newmtl", keyword))),
+        };
+        match keyword {
+            "Kd" => desc.kd = Some(parse_color(&args).map_err(err)?),
+            "Ks" => desc.ks = Some(parse_color(&args).map_err(err)?),
+            "Ke" => desc.ke = Some(parse_color(&args).map_err(err)?),
+            "Ns" => desc.ns = Some(parse_floats(&args, 1).map_err(err)?[0]),
+            "Ni" => desc.ni = Some(parse_floats(&args, 1).map_err(err)?[0]),
+            "d" => desc.dissolve = Some(parse_floats(&args, 1).map_err(err)?[0]),
+            "Tr" => desc.dissolve = Some(1. - parse_floats(&args, 1).map_err(err)?[0]),
+            "illum" => desc.illum = Some(args.first().and_then(|a| a.parse().ok()).ok_or_else(|| err("illum needs an integer".to_string()))?),
+            // Options such as `-bm` come before the file name, which is always last.
+            "map_Kd" => desc.map_kd = Some(dir.join(args.last().ok_or_else(|| err("map_Kd without a file name".to_string()))?)),
+            _ => {}
+        }
+    }
+    if let Some((name, desc)) = current.take() {
+        materials.insert(name, to_material(desc)?);
+    }
+    Ok(())
+}
+
+fn to_material(desc: MtlDesc) -> Result<Arc<dyn Material>, ObjError> {
+    let black = Spectrum::black();
+    if let Some(ke) = desc.ke {
+        if !ke.is_black() {
+            return Ok(Arc::new(material::DiffuseLight::new(ke)));
+        }
+    }
+    let transparent = desc.dissolve.is_some_and(|d| d < 1.) || matches!(desc.illum, Some(4) | Some(6) | Some(7) | Some(9));
+    if transparent {
+        return Ok(Arc::new(material::Dielectric{ir:desc.ni.unwrap_or(1.5)}));
+    }
+    let kd = desc.kd.unwrap_or(black);
+    let ks = desc.ks.unwrap_or(black);
+    if desc.map_kd.is_none() && ks.max_comp() > kd.max_comp() {
+        // Ns is the Phong exponent, which `Metal::exponent` gets back from the fuzz. Exponents
+        // under 3 are rougher than any fuzz and are clamped to fuzz 1; large ones approach a mirror.
+        let fuzz = (5./(desc.ns.unwrap_or(0.).max(0.) + 2.)).sqrt();
+        return Ok(Arc::new(material::Metal{albedo:ks, fuzz:f64::min(fuzz, 1.)}));
+    }
+    let albedo: Box<dyn Texture> = match desc.map_kd {
+        Some(file) => {
+            let tex = ImageTexture::open(&file).map_err(|source| ObjError::Texture{path:file.clone(), source})?;
+            Box::new(tex)
+        }
+        None => Box::new(SolidTexture{color:desc.kd.unwrap_or(Spectrum::new(0.8, 0.8, 0.8))}),
+    };
+    Ok(Arc::new(material::Lambertian{albedo}))
+}
+
+fn read(path: &Path) -> Result<String, ObjError> {
+    std::fs::read_to_string(path).map_err(|source| ObjError::Io{path:path.to_path_buf(), source})
+}
+
+// Splits a line into words, dropping comments.
+fn statement(line: &str) -> std::str::SplitWhitespace<'_> {
+    let line = match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    line.split_whitespace()
+}
+
+fn parse_floats(args: &[&str], min: usize) -> Result<Vec<f64>, String> {
+    if args.len() < min {
+        return Err(format!("expected at least {} numbers, found {}", min, args.len()));
+    }
+    args.iter().map(|a| a.parse::<f64>().map_err(|_| format!("`{}` is not a number", a))).collect()
+}
+
+fn parse_vec(args: &[&str], min: usize) -> Result<Vec3d, String> {
+    let v = parse_floats(args, min)?;
+    Ok(Vec3d::new(v[0], v[1], v[2]))
+}
+
+// `Kd r g b`, or a single value for grey.
+fn parse_color(args: &[&str]) -> Result<Spectrum, String> {
+    let v = parse_floats(args, 1)?;
+    match v.len() {
+        1 => Ok(Spectrum::new(v[0], v[0], v[0])),
+        3 => Ok(Spectrum::new(v[0], v[1], v[2])),
+        n => Err(format!("expected 1 or 3 color components, found {}", n)),
+    }
+}
+
+// Parses `v`, `v/vt`, `v//vn` or `v/vt/vn` into zero based indices, resolving negative (relative) ones.
+fn parse_corner(corner: &str, np: usize, nt: usize, nn: usize) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut parts = corner.split('/');
+    let p = parts.next().unwrap_or("");
+    let t = parts.next().unwrap_or("");
+    let n = parts.next().unwrap_or("");
+    if parts.next().is_some() {
+        return Err(format!("malformed face corner `{}`", corner));
+    }
+    let p = resolve(p, np, "vertex")?;
+    let t = if t.is_empty() {None} else {Some(resolve(t, nt, "texcoord")?)};
+    let n = if n.is_empty() {None} else {Some(resolve(n, nn, "normal")?)};
+    Ok((p, t, n))
+}
+
+fn resolve(index: &str, count: usize, what: &str) -> Result<usize, String> {
+    let i: i64 = index.parse().map_err(|_| format!("`{}` is not a {} index", index, what))?;
+    let resolved = if i > 0 {i - 1} else {count as i64 + i};
+    if i == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("{} index {} out of range, {} defined so far", what, i, count));
+    }
+    Ok(resolved as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `files` into a fresh directory and loads the first one.
+    fn load(test: &str, files: &[(&str, &str)]) -> Result<Vec<ObjMesh>, ObjError> {
+        let dir = std::env::temp_dir().join(format!("obj_test_{}_{}", std::process::id(), test));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, text) in files {
+            std::fs::write(dir.join(name), text).unwrap();
+        }
+        let result = load_obj_meshes(dir.join(files[0].0));
+        std::fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    fn parse_error(result: Result<Vec<ObjMesh>, ObjError>) -> (usize, String) {
+        match result {
+            Err(ObjError::Parse{line, msg, ..}) => (line, msg),
+            Err(e) => panic!("expected a parse error, got {}", e),
+            Ok(_) => panic!("expected a parse error, got meshes"),
+        }
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0.5 1.5 0\n";
+
+    #[test]
+    fn ngons_become_fans() {
+        let meshes = load("ngon", &[("a.obj", &format!("{}f 1 2 3 4\nf 1 2 3 5 4\n", SQUARE))]).unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].mesh.len(), 5);
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let meshes = load("negative", &[("a.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\nv 0 0 1\nf -4 -2 -1\n")]).unwrap();
+        let data = &meshes[0].mesh.data;
+        assert_eq!(meshes[0].mesh.len(), 2);
+        let [i0, i1, i2] = data.indices[1];
+        assert_eq!(data.positions[i0].x, 0.);
+        assert_eq!(data.positions[i1].y, 1.);
+        assert_eq!(data.positions[i2].z, 1.);
+    }
+
+    #[test]
+    fn missing_texcoords_and_normals() {
+        let (line, msg) = parse_error(load("no_vt", &[("a.obj", &format!("{}f 1/1 2/2 3/3\n", SQUARE))]));
+        assert_eq!(line, 6);
+        assert!(msg.contains("texcoord"), "{}", msg);
+        let (_, msg) = parse_error(load("no_vn", &[("a.obj", &format!("{}f 1//1 2//2 3//3\n", SQUARE))]));
+        assert!(msg.contains("normal"), "{}", msg);
+    }
+
+    #[test]
+    fn out_of_range_indices() {
+        let (_, msg) = parse_error(load("range", &[("a.obj", &format!("{}f 1 2 9\n", SQUARE))]));
+        assert!(msg.contains("out of range"), "{}", msg);
+        let (_, msg) = parse_error(load("zero", &[("a.obj", &format!("{}f 0 1 2\n", SQUARE))]));
+        assert!(msg.contains("out of range"), "{}", msg);
+        let (_, msg) = parse_error(load("before", &[("a.obj", "v 0 0 0\nf -2 -1 1\n")]));
+        assert!(msg.contains("out of range"), "{}", msg);
+    }
+
+    #[test]
+    fn bad_numbers() {
+        let (line, msg) = parse_error(load("bad_v", &[("a.obj", "v 0 0 0\nv 1 x 0\n")]));
+        assert_eq!(line, 2);
+        assert!(msg.contains("`x`"), "{}", msg);
+        parse_error(load("short_v", &[("a.obj", "v 1 2\n")]));
+        parse_error(load("bad_f", &[("a.obj", &format!("{}f 1 2 three\n", SQUARE))]));
+        parse_error(load("two_corners", &[("a.obj", &format!("{}f 1 2\n", SQUARE))]));
+        let (line, _) = parse_error(load("bad_kd", &[("a.obj", "mtllib a.mtl\n"), ("a.mtl", "newmtl m\nKd 1 2\n")]));
+        assert_eq!(line, 2);
+    }
+
+    #[test]
+    fn missing_mtl_file() {
+        match load("no_mtl", &[("a.obj", "mtllib missing.mtl\n")]) {
+            Err(ObjError::Io{path, ..}) => assert!(path.ends_with("missing.mtl")),
+            Err(e) => panic!("expected an io error, got {}", e),
+            Ok(_) => panic!("expected an io error, got meshes"),
+        }
+    }
+
+    #[test]
+    fn undefined_material() {
+        let (line, msg) = parse_error(load("undefined", &[("a.obj", &format!("{}usemtl nope\nf 1 2 3\n", SQUARE))]));
+        assert_eq!(line, 7);
+        assert!(msg.contains("nope"), "{}", msg);
+    }
+
+    #[test]
+    fn cube_with_materials() {
+        let obj = "mtllib cube.mtl
+v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1
+o cube
+usemtl grey
+f 1 4 3 2
+f 5 6 7 8
+f 1 2 6 5
+f 2 3 7 6
+f 4 8 7 3
+usemtl lamp
+f 1 5 8 4
+";
+        let mtl = "newmtl grey\nKd 0.5 0.5 0.5\n\nnewmtl lamp\nKe 4 4 4\n";
+        let meshes = load("cube", &[("cube.obj", obj), ("cube.mtl", mtl)]).unwrap();
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes.iter().map(|m| m.mesh.len()).sum::<usize>(), 12);
+        assert_eq!(meshes[0].name, "cube");
+        assert_eq!(meshes[0].material.as_deref(), Some("grey"));
+        assert_eq!(meshes[0].mesh.len(), 10);
+        assert!(!meshes[0].mesh.data.mat.is_emissive());
+        assert_eq!(meshes[1].material.as_deref(), Some("lamp"));
+        assert_eq!(meshes[1].mesh.len(), 2);
+        assert!(meshes[1].mesh.data.mat.is_emissive());
+        // Faces without `vn` or `vt` leave the buffers empty.
+        assert!(meshes[0].mesh.data.normals.is_empty() && meshes[0].mesh.data.uvs.is_empty());
+    }
+}
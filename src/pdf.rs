@@ -0,0 +1,206 @@
+use crate::vector::Vec3d;
+use crate::sampler::Sampler;
+use std::f64::consts::PI;
+
+// Orthonormal basis with `w` along a given direction.
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+    pub u: Vec3d,
+    pub v: Vec3d,
+    pub w: Vec3d,
+}
+
+impl Onb {
+    pub fn new(n: Vec3d) -> Onb {
+        let w = n.norm();
+        let a = if w.x.abs() > 0.9 {Vec3d::new(0., 1., 0.)} else {Vec3d::new(1., 0., 0.)};
+        let v = Vec3d::cross(w, a).norm();
+        let u = Vec3d::cross(w, v);
+        Onb{u, v, w}
+    }
+    pub fn local(&self, a: Vec3d) -> Vec3d {
+        a.x*self.u + a.y*self.v + a.z*self.w
+    }
+}
+
+// A distribution over directions. `value` is the density per unit solid angle.
+pub trait Pdf {
+    fn value(&self, dir: Vec3d) -> f64;
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d;
+}
+
+pub struct CosinePdf {
+    pub uvw: Onb,
+}
+
+impl CosinePdf {
+    pub fn new(n: Vec3d) -> CosinePdf {
+        CosinePdf{uvw:Onb::new(n)}
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, dir: Vec3d) -> f64 {
+        let cosine = Vec3d::dot(dir.norm(), self.uvw.w);
+        if cosine > 0. {cosine/PI} else {0.}
+    }
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        let (u1, u2) = sampler.get_2d();
+        self.uvw.local(cosine_direction(u1, u2))
+    }
+}
+
+pub struct UniformHemispherePdf {
+    pub uvw: Onb,
+}
+
+impl UniformHemispherePdf {
+    pub fn new(n: Vec3d) -> UniformHemispherePdf {
+        UniformHemispherePdf{uvw:Onb::new(n)}
+    }
+}
+
+impl Pdf for UniformHemispherePdf {
+    fn value(&self, dir: Vec3d) -> f64 {
+        if Vec3d::dot(dir, self.uvw.w) > 0. {1./(2.*PI)} else {0.}
+    }
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        let (u1, u2) = sampler.get_2d();
+        let z = u1;
+        let r = (1. - z*z).max(0.).sqrt();
+        let phi = 2.*PI*u2;
+        self.uvw.local(Vec3d::new(r*phi.cos(), r*phi.sin(), z))
+    }
+}
+
+pub struct UniformSpherePdf {
+}
+
+impl Pdf for UniformSpherePdf {
+    fn value(&self, _dir: Vec3d) -> f64 {
+        1./(4.*PI)
+    }
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        let (u1, u2) = sampler.get_2d();
+        sphere_direction(u1, u2)
+    }
+}
+
+// Phong lobe: density proportional to cos^exponent of the angle to `w`.
+pub struct PhongPdf {
+    pub uvw: Onb,
+    pub exponent: f64,
+}
+
+impl PhongPdf {
+    pub fn new(axis: Vec3d, exponent: f64) -> PhongPdf {
+        PhongPdf{uvw:Onb::new(axis), exponent}
+    }
+}
+
+impl Pdf for PhongPdf {
+    fn value(&self, dir: Vec3d) -> f64 {
+        let cosine = Vec3d::dot(dir.norm(), self.uvw.w);
+        if cosine > 0. {(self.exponent + 1.)/(2.*PI)*cosine.powf(self.exponent)} else {0.}
+    }
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        let (u1, u2) = sampler.get_2d();
+        let z = u1.powf(1./(self.exponent + 1.));
+        let r = (1. - z*z).max(0.).sqrt();
+        let phi = 2.*PI*u2;
+        self.uvw.local(Vec3d::new(r*phi.cos(), r*phi.sin(), z))
+    }
+}
+
+// Picks `p0` with probability `weight` and `p1` otherwise.
+pub struct MixturePdf<'a> {
+    pub p0: &'a dyn Pdf,
+    pub p1: &'a dyn Pdf,
+    pub weight: f64,
+}
+
+impl<'a> MixturePdf<'a> {
+    pub fn new(p0: &'a dyn Pdf, p1: &'a dyn Pdf, weight: f64) -> MixturePdf<'a> {
+        MixturePdf{p0, p1, weight}
+    }
+}
+
+impl<'a> Pdf for MixturePdf<'a> {
+    fn value(&self, dir: Vec3d) -> f64 {
+        self.weight*self.p0.value(dir) + (1. - self.weight)*self.p1.value(dir)
+    }
+    fn generate(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        if sampler.get_1d() < self.weight {
+            self.p0.generate(sampler)
+        }
+        else {
+            self.p1.generate(sampler)
+        }
+    }
+}
+
+// Cosine weighted direction around +z, from two uniform numbers.
+pub fn cosine_direction(u1: f64, u2: f64) -> Vec3d {
+    let r = u1.sqrt();
+    let phi = 2.*PI*u2;
+    Vec3d::new(r*phi.cos(), r*phi.sin(), (1. - u1).max(0.).sqrt())
+}
+
+pub fn sphere_direction(u1: f64, u2: f64) -> Vec3d {
+    let z = 1. - 2.*u1;
+    let r = (1. - z*z).max(0.).sqrt();
+    let phi = 2.*PI*u2;
+    Vec3d::new(r*phi.cos(), r*phi.sin(), z)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    const N: usize = 200000;
+
+    // Integral of the density over the sphere, estimated with uniform directions.
+    fn integral(pdf: &dyn Pdf) -> f64 {
+        let mut sampler = IndependentSampler::new(1);
+        let sum: f64 = (0..N).map(|_| {
+            let (u1, u2) = sampler.get_2d();
+            pdf.value(sphere_direction(u1, u2))
+        }).sum();
+        sum*4.*PI/N as f64
+    }
+
+    // Mean of z over generated directions.
+    fn mean_z(pdf: &dyn Pdf) -> f64 {
+        let mut sampler = IndependentSampler::new(2);
+        (0..N).map(|_| pdf.generate(&mut sampler).norm().z).sum::<f64>()/N as f64
+    }
+
+    #[test]
+    fn densities_integrate_to_one() {
+        let z = Vec3d::new(0., 0., 1.);
+        let cosine = CosinePdf::new(z);
+        let hemisphere = UniformHemispherePdf::new(z);
+        let phong = PhongPdf::new(z, 5.);
+        let mixture = MixturePdf::new(&cosine, &hemisphere, 0.3);
+        for pdf in [&cosine as &dyn Pdf, &hemisphere, &UniformSpherePdf{}, &phong, &mixture] {
+            let i = integral(pdf);
+            assert!((i - 1.).abs() < 0.02, "integral {}", i);
+        }
+    }
+
+    #[test]
+    fn samples_follow_the_density() {
+        let z = Vec3d::new(0., 0., 1.);
+        let cosine = CosinePdf::new(z);
+        let hemisphere = UniformHemispherePdf::new(z);
+        let phong = PhongPdf::new(z, 5.);
+        let mixture = MixturePdf::new(&cosine, &hemisphere, 0.3);
+        // The mean cosine of each, worked out by hand.
+        let expected = [2./3., 0.5, 6./7., 0.3*2./3. + 0.7*0.5];
+        for (pdf, e) in [&cosine as &dyn Pdf, &hemisphere, &phong, &mixture].iter().zip(expected) {
+            let m = mean_z(*pdf);
+            assert!((m - e).abs() < 0.01, "mean cosine {} instead of {}", m, e);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::vector::Vec3d;
+use crate::sampler::Sampler;
+
+const POINT_COUNT: usize = 256;
+
+// Gradient noise on the integer lattice, with Hermite smoothing between lattice points.
+#[derive(Clone, Debug)]
+pub struct Perlin {
+    grad: Vec<Vec3d>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    pub fn new(sampler: &mut dyn Sampler) -> Perlin {
+        let grad = (0..POINT_COUNT).map(|_| {
+            loop {
+                let v = Vec3d::rand_vec(-1., 1., sampler);
+                if v.lensq() > 1e-8 {
+                    return v.norm();
+                }
+            }
+        }).collect();
+        let perm_x = Perlin::permutation(sampler);
+        let perm_y = Perlin::permutation(sampler);
+        let perm_z = Perlin::permutation(sampler);
+        Perlin{grad, perm_x, perm_y, perm_z}
+    }
+    // Fisher-Yates shuffle of 0..POINT_COUNT.
+    fn permutation(sampler: &mut dyn Sampler) -> Vec<usize> {
+        let mut p: Vec<usize> = (0..POINT_COUNT).collect();
+        for i in (1..POINT_COUNT).rev() {
+            let j = usize::min((sampler.get_1d()*(i + 1) as f64) as usize, i);
+            p.swap(i, j);
+        }
+        p
+    }
+    // Signed noise, roughly in [-1, 1].
+    pub fn noise(&self, p: Vec3d) -> f64 {
+        let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (u, v, w) = (p.x - fx, p.y - fy, p.z - fz);
+        let (i, j, k) = (fx as i64, fy as i64, fz as i64);
+        let mut c = [[[Vec3d::zero(); 2]; 2]; 2];
+        for (di, ci) in c.iter_mut().enumerate() {
+            for (dj, cj) in ci.iter_mut().enumerate() {
+                for (dk, ck) in cj.iter_mut().enumerate() {
+                    let ix = self.perm_x[((i + di as i64) & 255) as usize];
+                    let iy = self.perm_y[((j + dj as i64) & 255) as usize];
+                    let iz = self.perm_z[((k + dk as i64) & 255) as usize];
+                    *ck = self.grad[ix ^ iy ^ iz];
+                }
+            }
+        }
+        trilinear(&c, u, v, w)
+    }
+    // Sum of `depth` octaves of absolute noise, each at twice the frequency and half the weight.
+    pub fn turb(&self, p: Vec3d, depth: u32) -> f64 {
+        let mut accum = 0.;
+        let mut temp_p = p;
+        let mut weight = 1.;
+        for _ in 0..depth {
+            accum += weight*self.noise(temp_p);
+            weight *= 0.5;
+            temp_p = 2.*temp_p;
+        }
+        accum.abs()
+    }
+    // Like `turb` but keeps the sign and normalizes by the total weight, so it stays in [-1, 1].
+    pub fn fbm(&self, p: Vec3d, octaves: u32) -> f64 {
+        let mut accum = 0.;
+        let mut total = 0.;
+        let mut temp_p = p;
+        let mut weight = 1.;
+        for _ in 0..octaves.max(1) {
+            accum += weight*self.noise(temp_p);
+            total += weight;
+            weight *= 0.5;
+            temp_p = 2.*temp_p;
+        }
+        accum/total
+    }
+}
+
+fn trilinear(c: &[[[Vec3d; 2]; 2]; 2], u: f64, v: f64, w: f64) -> f64 {
+    let uu = u*u*(3. - 2.*u);
+    let vv = v*v*(3. - 2.*v);
+    let ww = w*w*(3. - 2.*w);
+    let mut accum = 0.;
+    for (i, ci) in c.iter().enumerate() {
+        for (j, cj) in ci.iter().enumerate() {
+            for (k, g) in cj.iter().enumerate() {
+                let (fi, fj, fk) = (i as f64, j as f64, k as f64);
+                let weight = Vec3d::new(u - fi, v - fj, w - fk);
+                accum += (fi*uu + (1. - fi)*(1. - uu))
+                       * (fj*vv + (1. - fj)*(1. - vv))
+                       * (fk*ww + (1. - fk)*(1. - ww))
+                       * Vec3d::dot(*g, weight);
+            }
+        }
+    }
+    accum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    fn perlin(seed: u64) -> Perlin {
+        Perlin::new(&mut IndependentSampler::new(seed))
+    }
+
+    // Points spread over a few lattice cells in every direction, negative ones included.
+    fn points() -> Vec<Vec3d> {
+        let mut sampler = IndependentSampler::new(99);
+        (0..5000).map(|_| Vec3d::rand_vec(-20., 20., &mut sampler)).collect()
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        let p = perlin(1);
+        for x in points() {
+            let n = p.noise(x);
+            assert!((-1. ..=1.).contains(&n), "noise {} at {:?}", n, x);
+            let f = p.fbm(x, 5);
+            assert!((-1. ..=1.).contains(&f), "fbm {} at {:?}", f, x);
+            assert!(p.turb(x, 7) >= 0.);
+        }
+        // Gradient noise is zero on the lattice.
+        assert_eq!(p.noise(Vec3d::new(3., -2., 7.)), 0.);
+    }
+
+    #[test]
+    fn noise_is_deterministic_per_seed() {
+        let (a, b, c) = (perlin(4), perlin(4), perlin(5));
+        let xs = points();
+        assert!(xs.iter().all(|&x| a.noise(x) == b.noise(x) && a.turb(x, 7) == b.turb(x, 7)));
+        assert!(xs.iter().any(|&x| a.noise(x) != c.noise(x)));
+    }
+
+    #[test]
+    fn octaves_add_detail() {
+        let p = perlin(2);
+        let xs = points();
+        assert!(xs.iter().all(|&x| p.fbm(x, 1) == p.noise(x)));
+        assert!(xs.iter().any(|&x| p.fbm(x, 4) != p.fbm(x, 1)));
+        assert!(xs.iter().any(|&x| p.turb(x, 4) != p.turb(x, 1)));
+        // No octaves at all is no turbulence.
+        assert!(xs.iter().all(|&x| p.turb(x, 0) == 0.));
+    }
+}
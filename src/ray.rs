@@ -5,11 +5,17 @@ pub struct Ray {
     pub ori: Vec3d,
     pub dir: Vec3d,
     pub t: f64,
+    // Random bits from the path's sampler, for choices made while the ray is traced, such as
+    // how far it gets into a medium. Zero when the ray has none.
+    pub key: u64,
 }
 
 impl Ray {
     pub fn new(ori: Vec3d, dir: Vec3d, t: f64) -> Ray {
-        Ray {ori, dir, t}
+        Ray {ori, dir, t, key:0}
+    }
+    pub fn with_key(self, key: u64) -> Ray {
+        Ray{key, ..self}
     }
     pub fn at(&self, t: f64) -> Vec3d {
         self.ori + t*self.dir
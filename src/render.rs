@@ -0,0 +1,655 @@
+use crate::color::Spectrum;
+use crate::ray::Ray;
+use crate::shape::{Hit, Shape};
+use crate::scene::Scene;
+use crate::pdf::{MixturePdf, Pdf};
+use crate::material::BsdfPdf;
+use crate::camera::Camera;
+use crate::sampler;
+use crate::sampler::{Sampler, SamplerKind};
+use crate::tonemap::PostProcess;
+use crate::filter::{BoxFilter, Filter};
+use crate::film::{Film, Tile};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: i32,
+    pub tile_size: u32,
+    pub threads: usize,
+    pub seed: u64,
+    pub post: PostProcess,
+    pub strategy: Strategy,
+    // With roulette on, paths end by roulette, or at MAX_BOUNCES, and `max_depth` is not used.
+    pub roulette: Option<RussianRoulette>,
+    pub sampler: SamplerKind,
+    // When set, pixels take as many samples as `Adaptive` decides and `samples_per_pixel` is not used.
+    pub adaptive: Option<Adaptive>,
+    // Spreads each sample over the pixels around it. The default box keeps it in its own pixel.
+    pub filter: Arc<dyn Filter>,
+    // Renders and writes out only these pixels of the image. The camera still frames the whole
+    // image. Set through `with_crop`, which keeps it inside the image.
+    crop: Option<Tile>,
+}
+
+impl Settings {
+    pub fn new(width: u32, height: u32, samples_per_pixel: u32, max_depth: i32) -> Settings {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Settings{width, height, samples_per_pixel, max_depth, tile_size:16, threads, seed:sampler::seed(), post:PostProcess::default(), strategy:Strategy::Mis(Heuristic::Power), roulette:Some(RussianRoulette::default()), sampler:SamplerKind::Independent, adaptive:None, filter:Arc::new(BoxFilter::default()), crop:None}
+    }
+    // The most samples any pixel can take.
+    pub fn max_samples(&self) -> u32 {
+        match self.adaptive {
+            Some(a) => a.max_samples,
+            None => self.samples_per_pixel,
+        }
+    }
+    // How many samples a pixel's sampler lays out its strata for. An adaptive pixel stops only
+    // after whole rounds, so a stratified sampler covers one round at a time. The rest need
+    // room for every sample a pixel can take.
+    pub fn sampler_samples(&self) -> u32 {
+        match (self.adaptive, self.sampler) {
+            (Some(a), SamplerKind::Stratified) => a.round(),
+            _ => self.max_samples(),
+        }
+    }
+    // Crops to `crop`, clipped to the image. There is no crop window without pixels in the image.
+    pub fn with_crop(mut self, crop: Tile) -> Option<Settings> {
+        let c = crop.intersect(&Tile{x0:0, y0:0, x1:self.width, y1:self.height});
+        if c.is_empty() {
+            return None;
+        }
+        self.crop = Some(c);
+        Some(self)
+    }
+    pub fn crop(&self) -> Option<Tile> {
+        self.crop
+    }
+    // A sampler laid out for these settings. One serves every pixel a worker renders, since
+    // `start_pixel` resets it.
+    pub fn make_sampler(&self) -> Box<dyn Sampler> {
+        self.sampler.make(self.seed, self.sampler_samples(), self.width, self.height)
+    }
+    // The pixels that end up on the film: the crop window, or the whole image.
+    pub fn bounds(&self) -> Tile {
+        let full = Tile{x0:0, y0:0, x1:self.width, y1:self.height};
+        self.crop.map_or(full, |c| c.intersect(&full))
+    }
+    // How far a pixel's samples can land from it, in whole pixels.
+    pub fn filter_reach(&self) -> u32 {
+        (self.filter.radius() + 0.5).ceil() as u32
+    }
+    // The pixels that get sampled. Pixels just outside a crop window are sampled too, so the
+    // window's edges get the same filtered samples as they would in the whole image.
+    pub fn sample_bounds(&self) -> Tile {
+        let full = Tile{x0:0, y0:0, x1:self.width, y1:self.height};
+        self.bounds().grow(self.filter_reach()).intersect(&full)
+    }
+}
+
+// Keeps sampling a pixel until the standard error of its mean luminance is below `threshold`
+// times the mean, taking at least `min_samples` and at most `max_samples`. Means under
+// MIN_MEAN count as MIN_MEAN, so near black pixels do not chase a tiny relative error.
+// Convergence is checked only at power-of-two sample counts, in rounds of at least
+// `min_samples`, where stratified and Sobol samplers have spread their samples evenly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adaptive {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f64,
+}
+
+const MIN_MEAN: f64 = 0.01;
+
+impl Adaptive {
+    pub fn new(min_samples: u32, max_samples: u32, threshold: f64) -> Adaptive {
+        let min_samples = min_samples.max(2);
+        Adaptive{min_samples, max_samples:max_samples.max(min_samples), threshold}
+    }
+    // The fewest samples between checks.
+    pub fn round(&self) -> u32 {
+        self.min_samples.next_power_of_two()
+    }
+    pub fn converged(&self, n: u32, mean: f64, variance: f64) -> bool {
+        if n >= self.max_samples {
+            return true;
+        }
+        if n < self.round() || !n.is_power_of_two() {
+            return false;
+        }
+        (variance/n as f64).sqrt() <= self.threshold*mean.max(MIN_MEAN)
+    }
+}
+
+impl Default for Adaptive {
+    fn default() -> Adaptive {
+        Adaptive::new(16, 1024, 0.02)
+    }
+}
+
+// How the two ways of finding a light are combined: sampling the BSDF and hoping to hit one,
+// or sampling the lights and evaluating the BSDF. `Bsdf` and `Lights` use one strategy alone,
+// for debugging; the other's contribution is left out where it would overlap. `Mixture` traces
+// no shadow rays: the next direction itself comes from an even mix of the two densities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    Mis(Heuristic),
+    Bsdf,
+    Lights,
+    Mixture,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Heuristic {
+    Balance,
+    Power,
+}
+
+impl Heuristic {
+    // Weight of a sample taken with density `pa` when `pb` could also have produced it.
+    pub fn weight(&self, pa: f64, pb: f64) -> f64 {
+        if pa <= 0. {
+            return 0.;
+        }
+        match *self {
+            Heuristic::Balance => pa/(pa + pb),
+            Heuristic::Power => pa*pa/(pa*pa + pb*pb),
+        }
+    }
+}
+
+impl Strategy {
+    fn bsdf_weight(&self, bsdf_pdf: f64, light_pdf: f64) -> f64 {
+        match *self {
+            Strategy::Mis(h) => h.weight(bsdf_pdf, light_pdf),
+            Strategy::Bsdf => 1.,
+            Strategy::Lights => if light_pdf > 0. {0.} else {1.},
+            Strategy::Mixture => 1.,
+        }
+    }
+    fn light_weight(&self, light_pdf: f64, bsdf_pdf: f64) -> f64 {
+        match *self {
+            Strategy::Mis(h) => h.weight(light_pdf, bsdf_pdf),
+            Strategy::Bsdf => 0.,
+            Strategy::Lights => 1.,
+            Strategy::Mixture => 0.,
+        }
+    }
+}
+
+// From bounce `min_depth` on, a path continues with a probability that follows its throughput,
+// clamped to [min_survival, max_survival], and survivors are scaled up to make up for the
+// ones that stop. The probability never goes above MAX_SURVIVAL, so a path in a closed white
+// scene still ends.
+pub const MAX_SURVIVAL: f64 = 0.99;
+// Paths stop here even when roulette would let them go on, a bias far below the noise.
+pub const MAX_BOUNCES: i32 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RussianRoulette {
+    pub min_depth: i32,
+    pub min_survival: f64,
+    pub max_survival: f64,
+}
+
+impl RussianRoulette {
+    pub fn new(min_depth: i32, min_survival: f64, max_survival: f64) -> RussianRoulette {
+        RussianRoulette{min_depth, min_survival, max_survival}
+    }
+    pub fn survival(&self, throughput: Spectrum) -> f64 {
+        let hi = self.max_survival.min(MAX_SURVIVAL);
+        throughput.max_comp().clamp(self.min_survival.min(hi), hi)
+    }
+}
+
+impl Default for RussianRoulette {
+    fn default() -> RussianRoulette {
+        RussianRoulette::new(3, 0.05, 0.95)
+    }
+}
+
+// Sampler dimensions: the camera takes the first ones (pixel 2, lens 2, time 1), then every
+// bounce gets a block of its own, split between the BSDF (up to 3), the light sample (up to 4,
+// or 5 with the choice a mixture makes first), the roulette (1) and the keys of the bounce and
+// shadow rays (1 each).
+pub const CAMERA_DIMENSIONS: u32 = 5;
+pub const BOUNCE_DIMENSIONS: u32 = 11;
+const LIGHT_DIMENSION: u32 = 3;
+const ROULETTE_DIMENSION: u32 = 8;
+const RAY_KEY_DIMENSION: u32 = 9;
+const SHADOW_KEY_DIMENSION: u32 = 10;
+
+// Path tracing with next event estimation: at every non-specular vertex one direction towards
+// the lights is traced as well as the BSDF direction, and light reached either way is weighted
+// by `settings.strategy` so that nothing is counted twice.
+pub fn ray_color(r: &Ray, scene: &Scene, settings: &Settings, sampler: &mut dyn Sampler) -> Spectrum {
+    let mut radiance = Spectrum::black();
+    let mut throughput = Spectrum::white();
+    let mut ray = r.clone();
+    // The camera ray could not have been found by sampling the lights, like a specular bounce.
+    let mut specular = true;
+    let mut prev_pdf = 0.;
+    let mut prev_p = r.ori;
+    for bounce in 0.. {
+        if bounce > MAX_BOUNCES || (settings.roulette.is_none() && bounce > settings.max_depth) {
+            break;
+        }
+        let dim = CAMERA_DIMENSIONS + bounce as u32*BOUNCE_DIMENSIONS;
+        sampler.set_dimension(dim + RAY_KEY_DIMENSION);
+        ray.key = sampler.get_1d().to_bits();
+        let hit = scene.world.hit(&ray, 0.0001, f64::INFINITY);
+        let emitted = if hit.h {hit.mat.emitted(hit.u, hit.v, hit.p)} else {scene.environment.radiance(ray.dir)};
+        if !emitted.is_black() {
+            let weight = if specular {1.} else {
+                settings.strategy.bsdf_weight(prev_pdf, scene.light_pdf(prev_p).value(ray.dir))
+            };
+            radiance += throughput*emitted*weight;
+        }
+        if !hit.h {
+            break;
+        }
+        sampler.set_dimension(dim);
+        let scatter = hit.mat.scatter(&ray, &hit, sampler);
+        if !scatter.s {
+            break;
+        }
+        let mut weight = scatter.weight();
+        let mut pdf = scatter.pdf;
+        let mut next = scatter.r;
+        if !scatter.specular && matches!(settings.strategy, Strategy::Mis(_) | Strategy::Lights) {
+            sampler.set_dimension(dim + SHADOW_KEY_DIMENSION);
+            let key = sampler.get_1d().to_bits();
+            sampler.set_dimension(dim + LIGHT_DIMENSION);
+            radiance += throughput*sample_lights(&ray, &hit, scene, settings.strategy, key, sampler);
+        }
+        let light = scene.light_pdf(hit.p);
+        if !scatter.specular && settings.strategy == Strategy::Mixture && !light.is_empty() {
+            sampler.set_dimension(dim + LIGHT_DIMENSION);
+            let bsdf = BsdfPdf::new(&ray, &hit);
+            let mixture = MixturePdf::new(&light, &bsdf, 0.5);
+            let dir = mixture.generate(sampler);
+            if dir.near_zero() {
+                break;
+            }
+            pdf = mixture.value(dir);
+            weight = if pdf > 0. {hit.mat.eval(&ray, &hit, dir)/pdf} else {Spectrum::black()};
+            next = Ray::new(hit.p, dir, ray.t);
+        }
+        throughput = throughput*weight;
+        if throughput.is_black() {
+            break;
+        }
+        if let Some(rr) = settings.roulette {
+            if bounce >= rr.min_depth {
+                let q = rr.survival(throughput);
+                sampler.set_dimension(dim + ROULETTE_DIMENSION);
+                if sampler.get_1d() >= q {
+                    break;
+                }
+                throughput = throughput/q;
+            }
+        }
+        specular = scatter.specular;
+        prev_pdf = pdf;
+        prev_p = hit.p;
+        ray = next;
+    }
+    radiance
+}
+
+// Light reaching `hit` straight from one sampled point on the lights or the environment, times the BSDF.
+// `key` goes on the shadow ray.
+fn sample_lights(r: &Ray, hit: &Hit, scene: &Scene, strategy: Strategy, key: u64, sampler: &mut dyn Sampler) -> Spectrum {
+    let light = scene.light_pdf(hit.p);
+    if light.is_empty() {
+        return Spectrum::black();
+    }
+    let dir = light.generate(sampler);
+    let pdf = light.value(dir);
+    if pdf <= 0. {
+        return Spectrum::black();
+    }
+    let f = hit.mat.eval(r, hit, dir);
+    if f.is_black() {
+        return Spectrum::black();
+    }
+    let shadow = scene.world.hit(&Ray::new(hit.p, dir, r.t).with_key(key), 0.0001, f64::INFINITY);
+    let emitted = if shadow.h {shadow.mat.emitted(shadow.u, shadow.v, shadow.p)} else {scene.environment.radiance(dir)};
+    let weight = strategy.light_weight(pdf, hit.mat.pdf(r, hit, dir));
+    f*emitted*(weight/pdf)
+}
+
+// Samples pixel (i, j) into `film`, with a sampler from `Settings::make_sampler`.
+pub fn render_pixel(cam: &Camera, scene: &Scene, settings: &Settings, sampler: &mut dyn Sampler, i: u32, j: u32, film: &mut Film) {
+    sampler.start_pixel(i, j);
+    // Welford's running mean and variance of the luminance.
+    let mut mean = 0.;
+    let mut m2 = 0.;
+    let mut n = 0;
+    while n < settings.max_samples() {
+        sampler.start_sample(n);
+        let (du, dv) = sampler.get_2d();
+        let u = (i as f64 + du)/(settings.width as f64 - 1.);
+        let v = (j as f64 + dv)/(settings.height as f64 - 1.);
+        let r = cam.get_ray(u, v, sampler);
+        let c = ray_color(&r, scene, settings, sampler);
+        film.add_sample(i as f64 + du, j as f64 + dv, c, settings.filter.as_ref());
+        n += 1;
+        if let Some(adaptive) = settings.adaptive {
+            let x = c.luminance();
+            let d = x - mean;
+            mean += d/n as f64;
+            m2 += d*(x - mean);
+            if n > 1 && adaptive.converged(n, mean, m2/(n - 1) as f64) {
+                break;
+            }
+        }
+    }
+    film.add_count(i, j, n);
+}
+
+// Renders on the calling thread, column by column.
+pub fn render(cam: &Camera, scene: &Scene, settings: &Settings) -> Film {
+    let mut film = Film::with_region(settings.width, settings.height, settings.bounds());
+    let b = settings.sample_bounds();
+    let mut sampler = settings.make_sampler();
+    for i in b.x0..b.x1 {
+        for j in b.y0..b.y1 {
+            render_pixel(cam, scene, settings, sampler.as_mut(), i, j, &mut film);
+        }
+        let done = i + 1 - b.x0;
+        if done.is_multiple_of(10) {
+            println!("{0}/{1}", done, b.width());
+        }
+    }
+    film
+}
+
+pub fn tiles(settings: &Settings) -> Vec<Tile> {
+    let size = settings.tile_size.max(1);
+    let b = settings.sample_bounds();
+    let mut tiles = Vec::new();
+    for y0 in (b.y0..b.y1).step_by(size as usize) {
+        for x0 in (b.x0..b.x1).step_by(size as usize) {
+            let x1 = u32::min(x0 + size, b.x1);
+            let y1 = u32::min(y0 + size, b.y1);
+            tiles.push(Tile{x0, y0, x1, y1});
+        }
+    }
+    tiles
+}
+
+// Renders the same pixels as `render`, with the tiles shared out between `settings.threads` workers.
+// Each tile goes into a film of its own, grown by the filter's reach, and the films are merged in
+// tile order so the result does not depend on which worker took which tile. With the box filter
+// every pixel sums just its own samples, so the film is exactly the one `render` gives. Wider
+// filters sum samples from neighbouring tiles in another order, so pixels can differ from
+// `render` in the last bits.
+pub fn render_parallel(cam: &Camera, scene: &Scene, settings: &Settings) -> Film {
+    let tiles = tiles(settings);
+    let bounds = settings.bounds();
+    let reach = settings.filter_reach();
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let mut finished: Vec<(usize, Film)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..settings.threads.max(1)).map(|_| {
+            scope.spawn(|| {
+                let mut sampler = settings.make_sampler();
+                let mut out = Vec::new();
+                loop {
+                    let k = next.fetch_add(1, Ordering::Relaxed);
+                    if k >= tiles.len() {
+                        break;
+                    }
+                    let tile = tiles[k];
+                    let mut film = Film::with_region(settings.width, settings.height, tile.grow(reach).intersect(&bounds));
+                    for j in tile.y0..tile.y1 {
+                        for i in tile.x0..tile.x1 {
+                            render_pixel(cam, scene, settings, sampler.as_mut(), i, j, &mut film);
+                        }
+                    }
+                    out.push((k, film));
+                    let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    if n.is_multiple_of(10) {
+                        println!("{0}/{1}", n, tiles.len());
+                    }
+                }
+                out
+            })
+        }).collect();
+        workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+    });
+    finished.sort_by_key(|(k, _)| *k);
+    let mut film = Film::with_region(settings.width, settings.height, bounds);
+    for (_, part) in &finished {
+        film.merge(part);
+    }
+    film
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vec3d;
+    use crate::shape::{Objects, Sphere};
+    use crate::material::{Dielectric, DiffuseLight, Lambertian, Material};
+    use crate::environment::Environment;
+    use crate::camera::Camera;
+    use crate::filter::MitchellFilter;
+    use std::convert::TryInto;
+
+    // White spheres on a white ground under a white sky. Nothing absorbs, so every path
+    // brings back exactly 1 however many times it bounces.
+    fn furnace() -> Scene {
+        furnace_around(Arc::new(Lambertian::new(Spectrum::white())), 0.5)
+    }
+
+    // The same with the sphere in the middle made of `middle`, which must not absorb either.
+    fn furnace_around(middle: Arc<dyn Material>, middle_radius: f64) -> Scene {
+        let white = Arc::new(Lambertian::new(Spectrum::white()));
+        let mut world: Vec<Box<dyn Shape>> = vec![Box::new(Sphere{center:Vec3d::new(0., -1000., 0.), radius:1000., mat:white.clone()})];
+        for a in -2..3 {
+            for b in -2..3 {
+                let (mat, radius): (Arc<dyn Material>, f64) = if a == 0 && b == 0 {(middle.clone(), middle_radius)} else {(white.clone(), 0.5)};
+                world.push(Box::new(Sphere{center:Vec3d::new(a as f64, 0.45, b as f64), radius, mat}));
+            }
+        }
+        let mut scene = Scene::new(Objects::new(world), 0., 1.);
+        scene.environment = Environment::constant(Spectrum::white());
+        scene
+    }
+
+    fn mean_radiance(scene: &Scene, settings: &Settings, n: u32) -> f64 {
+        mean_radiance_from(scene, settings, n, Vec3d::new(0.2, 2., 0.3), 2.)
+    }
+
+    // The mean over `n` rays from `origin` down into a square cone `spread` wide at unit depth.
+    fn mean_radiance_from(scene: &Scene, settings: &Settings, n: u32, origin: Vec3d, spread: f64) -> f64 {
+        let mut sampler = settings.sampler.make(settings.seed, n, 1, 1);
+        sampler.start_pixel(0, 0);
+        let mut sum = 0.;
+        for k in 0..n {
+            sampler.start_sample(k);
+            let (u1, u2) = sampler.get_2d();
+            let r = Ray::new(origin, Vec3d::new(spread*(u1 - 0.5), -1., spread*(u2 - 0.5)), 0.);
+            sum += ray_color(&r, scene, settings, sampler.as_mut()).luminance();
+        }
+        sum/n as f64
+    }
+
+    #[test]
+    fn white_furnace_is_unbiased_with_roulette() {
+        let scene = furnace();
+        let mut settings = Settings::new(1, 1, 1, 3);
+        settings.seed = 5;
+        let mean = mean_radiance(&scene, &settings, 4000);
+        assert!((mean - 1.).abs() < 0.025, "furnace gives {}", mean);
+        // Cutting paths at `max_depth` instead loses the light of the longer ones.
+        settings.roulette = None;
+        let cut = mean_radiance(&scene, &settings, 4000);
+        assert!(cut < 0.98, "depth limited furnace gives {}", cut);
+    }
+
+    #[test]
+    fn roulette_ends_paths_that_never_lose_energy() {
+        let rr = RussianRoulette::new(0, 0.5, 1.);
+        assert_eq!(rr.survival(Spectrum::white()), MAX_SURVIVAL);
+        assert_eq!(RussianRoulette::new(0, 2., 0.5).survival(Spectrum::white()), 0.5);
+        let scene = furnace();
+        let mut settings = Settings::new(1, 1, 1, 3);
+        settings.roulette = Some(rr);
+        // Survivors are scaled by 1/0.99 a bounce for hundreds of bounces, so this is noisy;
+        // what matters is that it comes back at all.
+        let mean = mean_radiance(&scene, &settings, 100);
+        assert!(mean.is_finite() && (mean - 1.).abs() < 0.25, "furnace gives {}", mean);
+    }
+
+    #[test]
+    fn adaptive_bounds_are_clamped() {
+        let a = Adaptive::new(0, 0, 0.1);
+        assert_eq!((a.min_samples, a.max_samples), (2, 2));
+        let a = Adaptive::new(32, 8, 0.1);
+        assert_eq!((a.min_samples, a.max_samples), (32, 32));
+    }
+
+    #[test]
+    fn adaptive_stops_only_after_whole_rounds() {
+        // Any variance passes this threshold, so only the sample count decides.
+        let a = Adaptive::new(10, 100, 1e9);
+        assert_eq!(a.round(), 16);
+        let stops: Vec<u32> = (1..=100).filter(|&n| a.converged(n, 1., 1.)).collect();
+        assert_eq!(stops, vec![16, 32, 64, 100]);
+    }
+
+    #[test]
+    fn adaptive_stratified_sampler_covers_each_round() {
+        let mut settings = Settings::new(1, 1, 1, 3);
+        settings.adaptive = Some(Adaptive::new(10, 1000, 0.02));
+        settings.sampler = SamplerKind::Stratified;
+        assert_eq!(settings.sampler_samples(), 16);
+        let mut sampler = settings.make_sampler();
+        sampler.start_pixel(0, 0);
+        for round in 0..2 {
+            let mut strata: Vec<u32> = (0..16).map(|k| {
+                sampler.start_sample(16*round + k);
+                (sampler.get_1d()*16.) as u32
+            }).collect();
+            strata.sort();
+            assert_eq!(strata, (0..16).collect::<Vec<_>>());
+        }
+        settings.sampler = SamplerKind::ZSobol;
+        assert_eq!(settings.sampler_samples(), 1000);
+    }
+
+    #[test]
+    fn parallel_render_matches_serial_render() {
+        let scene = furnace();
+        let cam = Camera::new(Vec3d::new(0., 2., 6.), Vec3d::new(0., 0.5, 0.), Vec3d::new(0., 1., 0.), 40., 1.5, 0., 6., 0., 1.);
+        let mut settings = Settings::new(12, 8, 4, 5);
+        settings.seed = 3;
+        settings.tile_size = 5;
+        settings.threads = 3;
+        let serial = render(&cam, &scene, &settings);
+        let parallel = render_parallel(&cam, &scene, &settings);
+        assert_eq!(serial.sums, parallel.sums);
+        assert_eq!(serial.weights, parallel.weights);
+        assert_eq!(serial.samples, parallel.samples);
+        // Wider filters only round differently.
+        settings.filter = Arc::new(MitchellFilter::default());
+        let serial = render(&cam, &scene, &settings);
+        let parallel = render_parallel(&cam, &scene, &settings);
+        assert_eq!(serial.samples, parallel.samples);
+        for (a, b) in serial.weights.iter().zip(&parallel.weights) {
+            assert!((a - b).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn parallel_render_does_not_depend_on_threads_or_tiles() {
+        let scene = furnace();
+        let cam = Camera::new(Vec3d::new(0., 2., 6.), Vec3d::new(0., 0.5, 0.), Vec3d::new(0., 1., 0.), 40., 1.5, 0., 6., 0., 1.);
+        let mut settings = Settings::new(12, 8, 4, 5);
+        settings.seed = 3;
+        let expected = render(&cam, &scene, &settings);
+        for &(threads, tile_size) in &[(1, 16), (2, 3), (4, 5), (7, 1)] {
+            settings.threads = threads;
+            settings.tile_size = tile_size;
+            let film = render_parallel(&cam, &scene, &settings);
+            assert_eq!(film.sums, expected.sums, "{} threads, tiles of {}", threads, tile_size);
+        }
+    }
+
+    #[test]
+    fn renders_repeat_bit_for_bit_with_the_same_seed() {
+        let scene = furnace();
+        let cam = Camera::new(Vec3d::new(0., 2., 6.), Vec3d::new(0., 0.5, 0.), Vec3d::new(0., 1., 0.), 40., 1.5, 0.1, 6., 0., 1.);
+        let mut settings = Settings::new(12, 8, 4, 5);
+        settings.threads = 3;
+        settings.tile_size = 5;
+        for &kind in &[SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol, SamplerKind::ZSobol] {
+            settings.sampler = kind;
+            settings.seed = 7;
+            let first = render(&cam, &scene, &settings);
+            assert_eq!(render(&cam, &scene, &settings).sums, first.sums, "{:?}", kind);
+            let parallel = render_parallel(&cam, &scene, &settings);
+            assert_eq!(render_parallel(&cam, &scene, &settings).sums, parallel.sums, "{:?}", kind);
+            settings.seed = 8;
+            assert_ne!(render(&cam, &scene, &settings).sums, first.sums, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn radiance_above_one_reaches_the_film_output() {
+        // The camera sits inside a bright light, so every ray sees just the light.
+        let bright = Spectrum::new(4., 2., 1.5);
+        let world: Vec<Box<dyn Shape>> = vec![Box::new(Sphere{center:Vec3d::zero(), radius:10., mat:Arc::new(DiffuseLight::new(bright))})];
+        let scene = Scene::new(Objects::new(world), 0., 1.);
+        let cam = Camera::new(Vec3d::zero(), Vec3d::new(0., 0., -1.), Vec3d::new(0., 1., 0.), 40., 1., 0., 1., 0., 1.);
+        let film = render(&cam, &scene, &Settings::new(2, 2, 4, 5));
+        assert_eq!(film.pixel(1, 0), bright);
+        let path = std::env::temp_dir().join(format!("render_{}_bright.pfm", std::process::id()));
+        film.write_pfm(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let header = b"PF\n2 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let values: Vec<f32> = bytes[header.len()..].chunks(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+        assert_eq!(values.len(), 12);
+        assert!(values.chunks(3).all(|c| c == [4., 2., 1.5]), "{:?}", values);
+    }
+
+    #[test]
+    fn crop_is_clipped_to_the_image() {
+        let settings = Settings::new(12, 8, 4, 5).with_crop(Tile{x0:10, y0:2, x1:20, y1:4}).unwrap();
+        assert_eq!(settings.crop(), Some(Tile{x0:10, y0:2, x1:12, y1:4}));
+        assert_eq!(settings.bounds(), Tile{x0:10, y0:2, x1:12, y1:4});
+    }
+
+    #[test]
+    fn crop_outside_the_image_is_rejected() {
+        assert!(Settings::new(12, 8, 4, 5).with_crop(Tile{x0:20, y0:0, x1:30, y1:8}).is_none());
+        assert!(Settings::new(12, 8, 4, 5).with_crop(Tile{x0:3, y0:3, x1:3, y1:6}).is_none());
+        assert_eq!(Settings::new(12, 8, 4, 5).bounds(), Tile{x0:0, y0:0, x1:12, y1:8});
+    }
+
+    #[test]
+    fn glass_furnace_is_unbiased_with_roulette() {
+        // Every ray goes into the glass sphere first. It is kept clear of the ground and the
+        // spheres around it, so light does not get caught between them for hundreds of bounces.
+        let scene = furnace_around(Arc::new(Dielectric{ir:1.5}), 0.4);
+        let mut settings = Settings::new(1, 1, 1, 3);
+        settings.seed = 5;
+        let origin = Vec3d::new(0., 2., 0.);
+        let mean = mean_radiance_from(&scene, &settings, 16000, origin, 0.3);
+        assert!((mean - 1.).abs() < 0.025, "glass furnace gives {}", mean);
+        // Going in and out of the glass takes two of the three bounces, so most paths are cut
+        // off before they get back out.
+        settings.roulette = None;
+        let cut = mean_radiance_from(&scene, &settings, 16000, origin, 0.3);
+        assert!(cut < 0.5, "depth limited glass furnace gives {}", cut);
+    }
+}
@@ -0,0 +1,539 @@
+extern crate rand;
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+// Every sampler created after this call derives its streams from `seed`.
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+}
+
+pub fn seed() -> u64 {
+    SEED.load(Ordering::Relaxed)
+}
+
+// A stream of numbers in [0, 1) for each sample of a pixel. Every sample starts at dimension 0
+// and each number handed out moves one dimension further; `set_dimension` jumps to a fixed one,
+// so the same use (the lens, the first bounce's BSDF...) lines up across the samples of a pixel.
+pub trait Sampler {
+    fn start_pixel(&mut self, i: u32, j: u32);
+    fn start_sample(&mut self, index: u32);
+    fn set_dimension(&mut self, dim: u32);
+    fn get_1d(&mut self) -> f64;
+    fn get_2d(&mut self) -> (f64, f64) {
+        let u = self.get_1d();
+        let v = self.get_1d();
+        (u, v)
+    }
+    fn gen_range(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min)*self.get_1d()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    ZSobol,
+}
+
+impl SamplerKind {
+    pub fn make(&self, seed: u64, samples_per_pixel: u32, width: u32, height: u32) -> Box<dyn Sampler> {
+        match *self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+            SamplerKind::ZSobol => Box::new(ZSobolSampler::new(seed, samples_per_pixel, width, height)),
+        }
+    }
+}
+
+// Plain pseudo-random numbers; dimensions mean nothing to it.
+#[derive(Clone, Debug)]
+pub struct IndependentSampler {
+    pub seed: u64,
+    rng: StdRng,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler{seed, rng:StdRng::seed_from_u64(seed)}
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel(&mut self, i: u32, j: u32) {
+        // Each pixel gets its own stream, so the result does not depend on the order pixels are visited in.
+        self.rng = StdRng::seed_from_u64(mix(self.seed, ((i as u64) << 32) | j as u64));
+    }
+    fn start_sample(&mut self, _index: u32) {
+    }
+    fn set_dimension(&mut self, _dim: u32) {
+    }
+    fn get_1d(&mut self) -> f64 {
+        self.rng.gen::<f64>()
+    }
+}
+
+// Where a sampler is: which pixel, which sample of it, which dimension.
+#[derive(Clone, Copy, Debug, Default)]
+struct SampleState {
+    pixel: u64,
+    index: u32,
+    dim: u32,
+}
+
+impl SampleState {
+    // Hash of the seed, the pixel and the current dimension, for scrambling that dimension.
+    fn hash(&self, seed: u64) -> u64 {
+        mix(mix(seed, self.pixel), self.dim as u64)
+    }
+}
+
+// Jittered strata: for every dimension the samples of a pixel fall in different strata, the strata
+// shuffled differently per dimension. 2D requests use an nx by ny grid with nx*ny = samples_per_pixel.
+#[derive(Clone, Debug)]
+pub struct StratifiedSampler {
+    pub seed: u64,
+    pub samples_per_pixel: u32,
+    nx: u32,
+    ny: u32,
+    state: SampleState,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> StratifiedSampler {
+        let n = samples_per_pixel.max(1);
+        // The divisor of n closest to its square root, so the grid is as square as it can be.
+        let nx = (1..=n).filter(|d| n.is_multiple_of(*d) && d*d <= n).max().unwrap_or(1);
+        StratifiedSampler{seed, samples_per_pixel:n, nx, ny:n/nx, state:SampleState::default()}
+    }
+    fn stratum(&self, h: u64) -> u32 {
+        // Samples past the planned count start another round of the strata.
+        let round = self.state.index/self.samples_per_pixel;
+        permutation_element(self.state.index%self.samples_per_pixel, self.samples_per_pixel, mix(h, round as u64) as u32)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel(&mut self, i: u32, j: u32) {
+        self.state.pixel = ((i as u64) << 32) | j as u64;
+    }
+    fn start_sample(&mut self, index: u32) {
+        self.state.index = index;
+        self.state.dim = 0;
+    }
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let h = self.state.hash(self.seed);
+        let k = self.stratum(h);
+        let jitter = to_unit(mix(h, self.state.index as u64));
+        self.state.dim += 1;
+        (k as f64 + jitter)/self.samples_per_pixel as f64
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let h = self.state.hash(self.seed);
+        let k = self.stratum(h);
+        let (x, y) = (k%self.nx, k/self.nx);
+        let jx = to_unit(mix(h, (self.state.index as u64) << 1));
+        let jy = to_unit(mix(h, ((self.state.index as u64) << 1) | 1));
+        self.state.dim += 2;
+        ((x as f64 + jx)/self.nx as f64, (y as f64 + jy)/self.ny as f64)
+    }
+}
+
+// How many dimensions get their own prime base; later ones fall back to hashed random numbers.
+const HALTON_DIMENSIONS: usize = 128;
+
+// The Halton sequence, dimension d in the base of the d-th prime, with the digits of every
+// pixel and dimension shuffled by their own random permutation.
+#[derive(Clone, Debug)]
+pub struct HaltonSampler {
+    pub seed: u64,
+    primes: Vec<u64>,
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        let mut primes: Vec<u64> = Vec::with_capacity(HALTON_DIMENSIONS);
+        let mut n = 2u64;
+        while primes.len() < HALTON_DIMENSIONS {
+            if primes.iter().all(|p| !n.is_multiple_of(*p)) {
+                primes.push(n);
+            }
+            n += 1;
+        }
+        HaltonSampler{seed, primes, state:SampleState::default()}
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel(&mut self, i: u32, j: u32) {
+        self.state.pixel = ((i as u64) << 32) | j as u64;
+    }
+    fn start_sample(&mut self, index: u32) {
+        self.state.index = index;
+        self.state.dim = 0;
+    }
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let h = self.state.hash(self.seed);
+        let d = self.state.dim as usize;
+        self.state.dim += 1;
+        if d >= self.primes.len() {
+            return to_unit(mix(h, self.state.index as u64));
+        }
+        scrambled_radical_inverse(self.primes[d], self.state.index as u64, h)
+    }
+}
+
+// Radical inverse of `a` in `base`, every digit position permuted by its own permutation. The
+// digits past the last nonzero one are zeros that get permuted too, up to double precision.
+fn scrambled_radical_inverse(base: u64, mut a: u64, seed: u64) -> f64 {
+    let inv_base = 1./base as f64;
+    let mut inv_base_n = 1.;
+    let mut result = 0.;
+    let mut k = 0;
+    while 1. - inv_base_n < 1. {
+        let digit = a%base;
+        let perm = permutation_element(digit as u32, base as u32, mix(seed, k) as u32);
+        inv_base_n *= inv_base;
+        result += perm as f64*inv_base_n;
+        a /= base;
+        k += 1;
+    }
+    f64::min(result, ONE_MINUS_EPSILON)
+}
+
+// Sobol points padded per dimension: every 1D or 2D request uses the first one or two Sobol
+// dimensions, with the sample order shuffled and the values Owen scrambled by a hash of the
+// pixel and dimension. The shuffles keep the dimensions from correlating with each other.
+#[derive(Clone, Debug)]
+pub struct SobolSampler {
+    pub seed: u64,
+    state: SampleState,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler{seed, state:SampleState::default()}
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel(&mut self, i: u32, j: u32) {
+        self.state.pixel = ((i as u64) << 32) | j as u64;
+    }
+    fn start_sample(&mut self, index: u32) {
+        self.state.index = index;
+        self.state.dim = 0;
+    }
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let h = self.state.hash(self.seed);
+        self.state.dim += 1;
+        let index = owen_scramble(self.state.index, h as u32);
+        bits_to_unit(owen_scramble(sobol_0(index), (h >> 32) as u32))
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let h = self.state.hash(self.seed);
+        self.state.dim += 2;
+        let index = owen_scramble(self.state.index, h as u32);
+        let h2 = mix(h, 1);
+        (bits_to_unit(owen_scramble(sobol_0(index), h2 as u32)), bits_to_unit(owen_scramble(sobol_1(index), (h2 >> 32) as u32)))
+    }
+}
+
+// The 24 orderings of four digits.
+const PERMUTATIONS_4: [[u64; 4]; 24] = [
+    [0, 1, 2, 3], [0, 1, 3, 2], [0, 2, 1, 3], [0, 2, 3, 1], [0, 3, 2, 1], [0, 3, 1, 2],
+    [1, 0, 2, 3], [1, 0, 3, 2], [1, 2, 0, 3], [1, 2, 3, 0], [1, 3, 2, 0], [1, 3, 0, 2],
+    [2, 1, 0, 3], [2, 1, 3, 0], [2, 0, 1, 3], [2, 0, 3, 1], [2, 3, 0, 1], [2, 3, 1, 0],
+    [3, 1, 2, 0], [3, 1, 0, 2], [3, 2, 1, 0], [3, 2, 0, 1], [3, 0, 2, 1], [3, 0, 1, 2]];
+
+// One Sobol sequence for the whole image, walked in Morton (z) order of the pixels, with the
+// base 4 digits of the index shuffled per dimension. Neighbouring pixels then get samples that
+// complement each other, so at low sample counts the error looks like blue noise instead of
+// white noise. Ahmed and Wonka, "Screen-Space Blue-Noise Diffusion of Monte Carlo Sampling
+// Error via Hierarchical Ordering of Pixels" (2020), as done in pbrt-v4.
+#[derive(Clone, Debug)]
+pub struct ZSobolSampler {
+    pub seed: u64,
+    log2_spp: u32,
+    base4_digits: u32,
+    morton: u64,
+    state: SampleState,
+}
+
+impl ZSobolSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32, width: u32, height: u32) -> ZSobolSampler {
+        let log2_spp = samples_per_pixel.max(1).next_power_of_two().trailing_zeros();
+        let res = u32::max(width, height).max(1).next_power_of_two();
+        let base4_digits = res.trailing_zeros() + log2_spp.div_ceil(2);
+        ZSobolSampler{seed, log2_spp, base4_digits, morton:0, state:SampleState::default()}
+    }
+    // The index runs past 32 bits for large images, but the Sobol points only take 32 bits.
+    // The bits above them pick the scramble instead, so far apart pixels stay decorrelated.
+    fn sample_index(&self) -> u64 {
+        let morton = self.morton | self.state.index as u64;
+        let dim = self.state.dim as u64;
+        // With an odd power of two samples the last digit is base 2.
+        let odd = self.log2_spp & 1;
+        let mut index = 0;
+        for i in (odd..self.base4_digits).rev() {
+            let shift = 2*i - odd;
+            let digit = (morton >> shift) & 3;
+            let higher = morton >> (shift + 2);
+            let p = mix(self.seed, higher ^ 0x55555555u64.wrapping_mul(dim)) >> 40;
+            index |= PERMUTATIONS_4[(p%24) as usize][digit as usize] << shift;
+        }
+        if odd == 1 {
+            index |= (morton & 1) ^ (mix(self.seed, (morton >> 1) ^ 0x55555555u64.wrapping_mul(dim)) & 1);
+        }
+        index
+    }
+    fn scramble(&self, index: u64) -> u64 {
+        let high = index >> 32;
+        let seed = if high == 0 {self.seed} else {mix(self.seed, high)};
+        mix(seed, self.state.dim as u64)
+    }
+}
+
+impl Sampler for ZSobolSampler {
+    fn start_pixel(&mut self, i: u32, j: u32) {
+        self.state.pixel = ((i as u64) << 32) | j as u64;
+        self.morton = morton_2d(i, j) << self.log2_spp;
+    }
+    fn start_sample(&mut self, index: u32) {
+        self.state.index = index;
+        self.state.dim = 0;
+    }
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+    fn get_1d(&mut self) -> f64 {
+        let index = self.sample_index();
+        let h = self.scramble(index);
+        self.state.dim += 1;
+        bits_to_unit(owen_scramble(sobol_0(index as u32), h as u32))
+    }
+    fn get_2d(&mut self) -> (f64, f64) {
+        let index = self.sample_index();
+        let h = self.scramble(index);
+        self.state.dim += 2;
+        let index = index as u32;
+        (bits_to_unit(owen_scramble(sobol_0(index), h as u32)), bits_to_unit(owen_scramble(sobol_1(index), (h >> 32) as u32)))
+    }
+}
+
+// Interleaves the bits of x and y, x in the even bits.
+pub fn morton_2d(x: u32, y: u32) -> u64 {
+    let spread = |v: u32| {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000ffff0000ffff;
+        v = (v | (v << 8)) & 0x00ff00ff00ff00ff;
+        v = (v | (v << 4)) & 0x0f0f0f0f0f0f0f0f;
+        v = (v | (v << 2)) & 0x3333333333333333;
+        (v | (v << 1)) & 0x5555555555555555
+    };
+    spread(x) | (spread(y) << 1)
+}
+
+// First Sobol dimension: the van der Corput sequence in base 2.
+pub fn sobol_0(index: u32) -> u32 {
+    index.reverse_bits()
+}
+
+// Second Sobol dimension, from the direction numbers of the polynomial x + 1.
+pub fn sobol_1(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 == 1 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Nested uniform scrambling of the bits of `v`, after Burley, "Practical Hash-based Owen Scrambling" (2020).
+pub fn owen_scramble(v: u32, seed: u32) -> u32 {
+    let mut x = v.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+// Element `i` of a random permutation of 0..n chosen by `seed`, without building the permutation.
+// Kensler, "Correlated Multi-Jittered Sampling" (2013).
+pub fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let p = seed;
+    let mut w = n.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(p))%n
+}
+
+const ONE_MINUS_EPSILON: f64 = 1. - f64::EPSILON/2.;
+
+fn bits_to_unit(bits: u32) -> f64 {
+    f64::min(bits as f64/(1u64 << 32) as f64, ONE_MINUS_EPSILON)
+}
+
+fn to_unit(h: u64) -> f64 {
+    (h >> 11) as f64/(1u64 << 53) as f64
+}
+
+// SplitMix64 finalizer applied to the combination of two keys.
+pub fn mix(a: u64, b: u64) -> u64 {
+    let mut z = a ^ b.wrapping_add(0x9e3779b97f4a7c15).wrapping_add(a << 6).wrapping_add(a >> 2);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Uniform number in [0, 1) derived from the bits of `keys` and the global seed. Shapes use it
+// where they need randomness but have no sampler; the keys (a ray, say) are already random.
+pub fn hash_uniform(keys: &[u64]) -> f64 {
+    to_unit(keys.iter().fold(seed(), |h, &k| mix(h, k)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_1d(sampler: &mut dyn Sampler, i: u32, j: u32, spp: u32) -> Vec<f64> {
+        sampler.start_pixel(i, j);
+        (0..spp).map(|k| {
+            sampler.start_sample(k);
+            sampler.get_1d()
+        }).collect()
+    }
+
+    // The first 2D point of each of the `spp` samples of pixel (i, j), at dimension `dim`.
+    fn first_2d(sampler: &mut dyn Sampler, i: u32, j: u32, spp: u32, dim: u32) -> Vec<(f64, f64)> {
+        sampler.start_pixel(i, j);
+        (0..spp).map(|k| {
+            sampler.start_sample(k);
+            sampler.set_dimension(dim);
+            sampler.get_2d()
+        }).collect()
+    }
+
+    // Which of the nx by ny cells each point falls in, sorted.
+    fn cells(points: &[(f64, f64)], nx: u32, ny: u32) -> Vec<u32> {
+        let mut cells: Vec<u32> = points.iter().map(|&(x, y)| (y*ny as f64) as u32*nx + (x*nx as f64) as u32).collect();
+        cells.sort();
+        cells
+    }
+
+    #[test]
+    fn halton_is_stratified() {
+        let mut sampler = HaltonSampler::new(11);
+        // Dimension 0 is in base 2 and dimension 1 in base 3.
+        let points = first_2d(&mut sampler, 3, 5, 16, 0);
+        assert_eq!(cells(&points, 16, 1), (0..16).collect::<Vec<_>>());
+        let points = first_2d(&mut sampler, 3, 5, 9, 0);
+        assert_eq!(cells(&points, 1, 9), (0..9).collect::<Vec<_>>());
+        // Together 2^4 by 3^2 samples fill every cell of a 16 by 9 grid once.
+        let points = first_2d(&mut sampler, 3, 5, 144, 0);
+        assert_eq!(cells(&points, 16, 9), (0..144).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sobol_is_stratified() {
+        let mut sampler = SobolSampler::new(11);
+        for dim in [0, 6] {
+            let points = first_2d(&mut sampler, 3, 5, 16, dim);
+            for (nx, ny) in [(16, 1), (8, 2), (4, 4), (2, 8), (1, 16)] {
+                assert_eq!(cells(&points, nx, ny), (0..16).collect::<Vec<_>>());
+            }
+            sampler.start_pixel(3, 5);
+            let mut xs: Vec<f64> = (0..16).map(|k| {
+                sampler.start_sample(k);
+                sampler.set_dimension(dim);
+                sampler.get_1d()
+            }).collect();
+            xs.sort_by(f64::total_cmp);
+            assert!(xs.iter().enumerate().all(|(k, x)| (x*16.) as usize == k));
+        }
+    }
+
+    #[test]
+    fn samplers_are_deterministic_whatever_pixel_came_before() {
+        for kind in [SamplerKind::Independent, SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol, SamplerKind::ZSobol] {
+            let mut a = kind.make(9, 16, 8, 8);
+            let mut b = kind.make(9, 16, 8, 8);
+            first_2d(b.as_mut(), 6, 1, 16, 0);
+            assert_eq!(first_2d(a.as_mut(), 2, 3, 16, 4), first_2d(b.as_mut(), 2, 3, 16, 4), "{:?}", kind);
+            assert_eq!(first_1d(a.as_mut(), 2, 3, 16), first_1d(b.as_mut(), 2, 3, 16), "{:?}", kind);
+            assert_ne!(first_1d(a.as_mut(), 2, 3, 16), first_1d(a.as_mut(), 3, 2, 16), "{:?}", kind);
+            let mut c = kind.make(10, 16, 8, 8);
+            assert_ne!(first_1d(a.as_mut(), 2, 3, 16), first_1d(c.as_mut(), 2, 3, 16), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn zsobol_index_is_not_truncated() {
+        // 65536 by 65536 pixels at 256 samples needs 40 bits of index.
+        let spp = 256;
+        let mut sampler = ZSobolSampler::new(7, spp, 1 << 16, 1 << 16);
+        let far = 1 << 15;
+        sampler.start_pixel(far, far);
+        sampler.start_sample(0);
+        assert!(sampler.sample_index() > u32::MAX as u64);
+        let near = first_1d(&mut sampler, 0, 0, spp);
+        let far = first_1d(&mut sampler, far, far, spp);
+        assert_ne!(near, far);
+        // Each pixel still gets one sample in each stratum.
+        for xs in [near, far] {
+            let mut strata = xs.iter().map(|x| (x*spp as f64) as u32).collect::<Vec<_>>();
+            strata.sort();
+            assert_eq!(strata, (0..spp).collect::<Vec<_>>());
+        }
+    }
+}
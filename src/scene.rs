@@ -0,0 +1,58 @@
+use crate::vector::Vec3d;
+use crate::shape::{Objects, Shape};
+use crate::bvh::{BvhBuilder, BvhNode};
+use crate::light::{LightPdf, Lights};
+use crate::environment::Environment;
+use std::sync::Arc;
+
+// The shapes to render, behind a BVH, the emitters among them for direct lighting, and
+// what lies beyond them. The environment is the gradient sky unless set otherwise.
+// Only the top-level shapes are asked `is_light`: an emitter inside a group such as `Objects`
+// or a `Cuboid` is never sampled directly. It still lights the scene when paths hit it, only
+// with more noise, so emitters should be added to the scene on their own. An `Instance` or a
+// `TriangleMesh` at the top level is fine.
+pub struct Scene {
+    pub world: BvhNode,
+    pub lights: Lights,
+    pub environment: Environment,
+}
+
+impl Scene {
+    pub fn new(objects: Objects, t0: f64, t1: f64) -> Scene {
+        Scene::with_builder(objects, t0, t1, &BvhBuilder::default())
+    }
+    pub fn with_builder(objects: Objects, t0: f64, t1: f64, builder: &BvhBuilder) -> Scene {
+        let shapes: Vec<Arc<dyn Shape>> = objects.object.into_iter().map(Arc::from).collect();
+        let lights = Lights::new(shapes.iter().filter(|s| s.is_light()).cloned().collect());
+        let world = builder.build(shapes, t0, t1);
+        Scene{world, lights, environment:Environment::default()}
+    }
+    // What direct lighting samples from `origin`: the lights, and the environment when it is sampled.
+    pub fn light_pdf(&self, origin: Vec3d) -> LightPdf<'_> {
+        let environment = if self.environment.is_light() {Some(&self.environment)} else {None};
+        LightPdf::new(&self.lights, environment, origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::XZRect;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::transform::{Instance, Transform};
+    use crate::color::Spectrum;
+
+    fn lamp() -> XZRect {
+        XZRect{x0:0., x1:1., z0:0., z1:1., k:1., mat:Arc::new(DiffuseLight::new(Spectrum::white()))}
+    }
+
+    #[test]
+    fn only_top_level_emitters_are_lights() {
+        let wall = XZRect{x0:0., x1:1., z0:0., z1:1., k:0., mat:Arc::new(Lambertian::new(Spectrum::white()))};
+        let moved = Instance::new(Arc::new(lamp()), Transform::translate(Vec3d::new(2., 0., 0.)));
+        let group = Objects::new(vec![Box::new(lamp())]);
+        let scene = Scene::new(Objects::new(vec![Box::new(lamp()), Box::new(wall), Box::new(moved), Box::new(group)]), 0., 1.);
+        // The lamp and the instanced lamp; the one inside the group is left out.
+        assert_eq!(scene.lights.len(), 2);
+    }
+}
@@ -3,17 +3,49 @@ use crate::ray::Ray;
 use crate::material::Material;
 use crate::material;
 use crate::aabb::AABB;
+use crate::sampler::Sampler;
+use crate::pdf::{sphere_direction, Onb};
+use std::f64::consts::PI;
 use std::sync::Arc;
 
-pub trait Shape{
+pub trait Shape: Send + Sync {
     fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit;
     fn bound(&self, t0: f64, t1: f64) -> AABB;
+    // Whether the shape emits light and can be sampled with `random`, so it belongs in the light list.
+    fn is_light(&self) -> bool {
+        false
+    }
+    // Solid angle density, seen from `o`, of `random` returning direction `v`.
+    fn pdf_value(&self, _o: Vec3d, _v: Vec3d) -> f64 {
+        0.
+    }
+    // A direction from `o` towards a point on the shape.
+    fn random(&self, _o: Vec3d, _sampler: &mut dyn Sampler) -> Vec3d {
+        Vec3d::new(1., 0., 0.)
+    }
+}
+
+// Solid angle density of a point picked uniformly over `area` on `shape`, hit from `o` along `v`.
+pub fn area_pdf(shape: &dyn Shape, o: Vec3d, v: Vec3d, area: f64) -> f64 {
+    let hit = shape.hit(&Ray::new(o, v, 0.), 0.0001, f64::INFINITY);
+    if !hit.h {
+        return 0.;
+    }
+    let dist_sq = hit.t*hit.t*v.lensq();
+    let cosine = Vec3d::dot(v.norm(), hit.ng).abs();
+    if cosine <= 0. {
+        return 0.;
+    }
+    dist_sq/(cosine*area)
 }
 
 #[derive(Clone)]
 pub struct Hit {
     pub p: Vec3d,
     pub n: Vec3d,
+    // The surface's own normal, on the same side as `n`. Only smooth shaded triangles,
+    // whose `n` is interpolated, have them differ.
+    pub ng: Vec3d,
     pub t: f64,
     pub h: bool,
     pub u: f64,
@@ -24,11 +56,12 @@ pub struct Hit {
 
 impl Hit {
     pub fn miss() -> Hit {
-        Hit{ p:Vec3d::zero(), n:Vec3d::zero(), t:f64::INFINITY, h:false, u:0., v:0., f:false, mat:Arc::new(material::Nothing{})}
+        Hit{ p:Vec3d::zero(), n:Vec3d::zero(), ng:Vec3d::zero(), t:f64::INFINITY, h:false, u:0., v:0., f:false, mat:Arc::new(material::Nothing{})}
     }
     pub fn set_face(r: &Ray, out_norm: Vec3d) -> bool {
         Vec3d::dot(r.dir, out_norm) < 0.
     }
+    #[allow(clippy::bool_comparison, clippy::needless_return)]
     pub fn set_norm(f: bool, out_norm: Vec3d) -> Vec3d {
         if f == true {
             return out_norm;
@@ -50,6 +83,7 @@ impl Objects {
 }
 
 impl Shape for Objects {
+    #[allow(clippy::bool_comparison)]
     fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
         if self.bound(tmin, tmax).hit(r, tmin, tmax) == false {
             return Hit::miss();
@@ -63,8 +97,9 @@ impl Shape for Objects {
         }
         curr_hit           
     }
+    #[allow(clippy::bool_comparison)]
     fn bound(&self, t0: f64, t1: f64) -> AABB {
-        if self.object.len() == 0 {
+        if self.object.is_empty() {
             return AABB::zero();
         }
         let mut first_box = true;
@@ -85,6 +120,7 @@ pub struct Sphere {
 }
 
 impl Shape for Sphere {
+    #[allow(clippy::bool_comparison)]
     fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
         if self.bound(tmin, tmax).hit(r, tmin, tmax) == false {
             return Hit::miss();
@@ -114,13 +150,44 @@ impl Shape for Sphere {
         let f = Hit::set_face(r, out_norm);
         let n = Hit::set_norm(f, out_norm).norm();
         let (u, v) = self.get_sphere_uv(out_norm);
-        Hit{t, p, n, h, u, v, f, mat:Arc::clone(&self.mat)}
+        Hit{t, p, n, ng:n, h, u, v, f, mat:Arc::clone(&self.mat)}
     }
     fn bound(&self, _: f64, _: f64) -> AABB {
         let p1 = self.center - self.radius*Vec3d::one();
         let p2 = self.center + self.radius*Vec3d::one();
         AABB::new(p1, p2)
     }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        let dist_sq = (self.center - o).lensq();
+        let r_sq = self.radius*self.radius;
+        if dist_sq <= r_sq {
+            return area_pdf(self, o, v, 4.*PI*r_sq);
+        }
+        if !self.hit(&Ray::new(o, v, 0.), 0.0001, f64::INFINITY).h {
+            return 0.;
+        }
+        let cos_theta_max = (1. - r_sq/dist_sq).sqrt();
+        1./(2.*PI*(1. - cos_theta_max))
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        let (u1, u2) = sampler.get_2d();
+        let dir = self.center - o;
+        let dist_sq = dir.lensq();
+        let r_sq = self.radius*self.radius;
+        // From inside there is no cone to sample, so pick a point on the surface instead.
+        if dist_sq <= r_sq {
+            return self.center + self.radius*sphere_direction(u1, u2) - o;
+        }
+        // Uniform over the cone of directions the sphere subtends.
+        let cos_theta_max = (1. - r_sq/dist_sq).sqrt();
+        let z = 1. + u2*(cos_theta_max - 1.);
+        let phi = 2.*PI*u1;
+        let s = (1. - z*z).max(0.).sqrt();
+        Onb::new(dir).local(Vec3d::new(phi.cos()*s, phi.sin()*s, z))
+    }
 }
 
 impl Sphere {
@@ -176,7 +243,7 @@ impl Shape for MovingSphere {
         let f = Hit::set_face(r, out_norm);
         let n = Hit::set_norm(f, out_norm).norm();
         let (u, v) = self.get_sphere_uv(out_norm);
-        Hit{t, p, n, h, u, v, f, mat:Arc::clone(&self.mat)}
+        Hit{t, p, n, ng:n, h, u, v, f, mat:Arc::clone(&self.mat)}
     }
     fn bound(&self, t0: f64, t1: f64) -> AABB {
         let p00 = self.center(t0) - self.radius*Vec3d::one();
@@ -195,4 +262,267 @@ impl MovingSphere {
         let v = theta/std::f64::consts::PI;
         (u, v)
     }
-}
\ No newline at end of file
+}
+
+// Shared by the three axis-aligned rectangles. The rectangle spans [a0, a1] x [b0, b1]
+// on axes `a` and `b` and sits at `k` on the remaining axis `c`.
+fn rect_hit(r: &Ray, tmin: f64, tmax: f64, (a0, a1, b0, b1, k): (f64, f64, f64, f64, f64), (a, b, c): (usize, usize, usize), mat: &Arc<dyn Material>) -> Hit {
+    let t = (k - r.ori[c])/r.dir[c];
+    if !(t >= tmin && t <= tmax) {
+        return Hit::miss();
+    }
+    let p = r.at(t);
+    if p[a] < a0 || p[a] > a1 || p[b] < b0 || p[b] > b1 {
+        return Hit::miss();
+    }
+    let u = (p[a] - a0)/(a1 - a0);
+    let v = (p[b] - b0)/(b1 - b0);
+    let mut out_norm = Vec3d::zero();
+    match c {
+        0 => out_norm.x = 1.,
+        1 => out_norm.y = 1.,
+        _ => out_norm.z = 1.,
+    }
+    let h = true;
+    let f = Hit::set_face(r, out_norm);
+    let n = Hit::set_norm(f, out_norm);
+    Hit{t, p, n, ng:n, h, u, v, f, mat:Arc::clone(mat)}
+}
+
+fn rect_random(o: Vec3d, (a0, a1, b0, b1, k): (f64, f64, f64, f64, f64), (a, b, c): (usize, usize, usize), sampler: &mut dyn Sampler) -> Vec3d {
+    let (u1, u2) = sampler.get_2d();
+    let mut p = [0.; 3];
+    p[a] = a0 + u1*(a1 - a0);
+    p[b] = b0 + u2*(b1 - b0);
+    p[c] = k;
+    Vec3d::new(p[0], p[1], p[2]) - o
+}
+
+// The rectangles have no thickness, so their boxes are padded along the normal axis.
+const RECT_PAD: f64 = 0.0001;
+
+#[derive(Clone)]
+pub struct XYRect {
+    pub x0: f64,
+    pub x1: f64,
+    pub y0: f64,
+    pub y1: f64,
+    pub k: f64,
+    pub mat: Arc<dyn Material>,
+}
+
+impl Shape for XYRect {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        rect_hit(r, tmin, tmax, (self.x0, self.x1, self.y0, self.y1, self.k), (0, 1, 2), &self.mat)
+    }
+    fn bound(&self, _: f64, _: f64) -> AABB {
+        AABB::new(Vec3d::new(self.x0, self.y0, self.k - RECT_PAD), Vec3d::new(self.x1, self.y1, self.k + RECT_PAD))
+    }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        area_pdf(self, o, v, (self.x1 - self.x0)*(self.y1 - self.y0))
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        rect_random(o, (self.x0, self.x1, self.y0, self.y1, self.k), (0, 1, 2), sampler)
+    }
+}
+
+#[derive(Clone)]
+pub struct XZRect {
+    pub x0: f64,
+    pub x1: f64,
+    pub z0: f64,
+    pub z1: f64,
+    pub k: f64,
+    pub mat: Arc<dyn Material>,
+}
+
+impl Shape for XZRect {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        rect_hit(r, tmin, tmax, (self.x0, self.x1, self.z0, self.z1, self.k), (0, 2, 1), &self.mat)
+    }
+    fn bound(&self, _: f64, _: f64) -> AABB {
+        AABB::new(Vec3d::new(self.x0, self.k - RECT_PAD, self.z0), Vec3d::new(self.x1, self.k + RECT_PAD, self.z1))
+    }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        area_pdf(self, o, v, (self.x1 - self.x0)*(self.z1 - self.z0))
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        rect_random(o, (self.x0, self.x1, self.z0, self.z1, self.k), (0, 2, 1), sampler)
+    }
+}
+
+#[derive(Clone)]
+pub struct YZRect {
+    pub y0: f64,
+    pub y1: f64,
+    pub z0: f64,
+    pub z1: f64,
+    pub k: f64,
+    pub mat: Arc<dyn Material>,
+}
+
+impl Shape for YZRect {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        rect_hit(r, tmin, tmax, (self.y0, self.y1, self.z0, self.z1, self.k), (1, 2, 0), &self.mat)
+    }
+    fn bound(&self, _: f64, _: f64) -> AABB {
+        AABB::new(Vec3d::new(self.k - RECT_PAD, self.y0, self.z0), Vec3d::new(self.k + RECT_PAD, self.y1, self.z1))
+    }
+    fn is_light(&self) -> bool {
+        self.mat.is_emissive()
+    }
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        area_pdf(self, o, v, (self.y1 - self.y0)*(self.z1 - self.z0))
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        rect_random(o, (self.y0, self.y1, self.z0, self.z1, self.k), (1, 2, 0), sampler)
+    }
+}
+
+// `shape` turned inside out: what it sees as its front face is the back face, and the other way round.
+pub struct FlipFace {
+    pub shape: Box<dyn Shape>,
+}
+
+impl Shape for FlipFace {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        let mut hit = self.shape.hit(r, tmin, tmax);
+        hit.f = !hit.f;
+        hit
+    }
+    fn bound(&self, t0: f64, t1: f64) -> AABB {
+        self.shape.bound(t0, t1)
+    }
+    fn is_light(&self) -> bool {
+        self.shape.is_light()
+    }
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        self.shape.pdf_value(o, v)
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        self.shape.random(o, sampler)
+    }
+}
+
+// An axis-aligned box made of six rectangles. Named `Cuboid` so it does not shadow `std::boxed::Box`.
+// Rectangles face their positive axis, so the three on the low sides are flipped to face out.
+pub struct Cuboid {
+    pub p0: Vec3d,
+    pub p1: Vec3d,
+    pub sides: Objects,
+}
+
+impl Cuboid {
+    pub fn new(p0: Vec3d, p1: Vec3d, mat: Arc<dyn Material>) -> Cuboid {
+        let (p0, p1) = (Vec3d::comp_min(p0, p1), Vec3d::comp_max(p0, p1));
+        let sides: Vec<Box<dyn Shape>> = vec![
+            Box::new(XYRect{x0:p0.x, x1:p1.x, y0:p0.y, y1:p1.y, k:p1.z, mat:mat.clone()}),
+            Box::new(FlipFace{shape:Box::new(XYRect{x0:p0.x, x1:p1.x, y0:p0.y, y1:p1.y, k:p0.z, mat:mat.clone()})}),
+            Box::new(XZRect{x0:p0.x, x1:p1.x, z0:p0.z, z1:p1.z, k:p1.y, mat:mat.clone()}),
+            Box::new(FlipFace{shape:Box::new(XZRect{x0:p0.x, x1:p1.x, z0:p0.z, z1:p1.z, k:p0.y, mat:mat.clone()})}),
+            Box::new(YZRect{y0:p0.y, y1:p1.y, z0:p0.z, z1:p1.z, k:p1.x, mat:mat.clone()}),
+            Box::new(FlipFace{shape:Box::new(YZRect{y0:p0.y, y1:p1.y, z0:p0.z, z1:p1.z, k:p0.x, mat})}),
+        ];
+        Cuboid{p0, p1, sides:Objects::new(sides)}
+    }
+}
+
+impl Shape for Cuboid {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        self.sides.hit(r, tmin, tmax)
+    }
+    fn bound(&self, _: f64, _: f64) -> AABB {
+        AABB::new(self.p0, self.p1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::Spectrum;
+    use crate::material::Lambertian;
+
+    fn white() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(Spectrum::white()))
+    }
+
+    // `v` with its `axis` component set to `x`.
+    fn with(v: Vec3d, axis: usize, x: f64) -> Vec3d {
+        let mut c = [v.x, v.y, v.z];
+        c[axis] = x;
+        Vec3d::new(c[0], c[1], c[2])
+    }
+
+    // Shoots at `target` from `offset` away from it.
+    fn shoot(shape: &dyn Shape, target: Vec3d, offset: Vec3d) -> Hit {
+        shape.hit(&Ray::new(target + offset, -offset, 0.), 0.001, f64::INFINITY)
+    }
+
+    #[test]
+    fn rect_uvs_run_from_corner_to_corner() {
+        let xy = XYRect{x0:1., x1:3., y0:-1., y1:2., k:0.5, mat:white()};
+        let xz = XZRect{x0:1., x1:3., z0:-1., z1:2., k:0.5, mat:white()};
+        let yz = YZRect{y0:1., y1:3., z0:-1., z1:2., k:0.5, mat:white()};
+        type Rect<'a> = (&'a dyn Shape, fn(f64, f64) -> Vec3d, Vec3d);
+        let rects: [Rect; 3] = [
+            (&xy, |a, b| Vec3d::new(a, b, 0.5), Vec3d::new(0., 0., 2.)),
+            (&xz, |a, b| Vec3d::new(a, 0.5, b), Vec3d::new(0., 2., 0.)),
+            (&yz, |a, b| Vec3d::new(0.5, a, b), Vec3d::new(2., 0., 0.)),
+        ];
+        for (rect, at, offset) in rects {
+            for &(a, b, u, v) in &[(1., -1., 0., 0.), (3., -1., 1., 0.), (1., 2., 0., 1.), (3., 2., 1., 1.), (2., 0.5, 0.5, 0.5)] {
+                let hit = shoot(rect, at(a, b), offset);
+                assert!(hit.h);
+                assert!((hit.u - u).abs() < 1e-12 && (hit.v - v).abs() < 1e-12, "({}, {}) at ({}, {})", hit.u, hit.v, a, b);
+            }
+            assert!(!shoot(rect, at(3.1, 0.), offset).h);
+        }
+    }
+
+    #[test]
+    fn rect_bounds_have_thickness() {
+        let rects: [Box<dyn Shape>; 3] = [
+            Box::new(XYRect{x0:0., x1:1., y0:0., y1:1., k:2., mat:white()}),
+            Box::new(XZRect{x0:0., x1:1., z0:0., z1:1., k:2., mat:white()}),
+            Box::new(YZRect{y0:0., y1:1., z0:0., z1:1., k:2., mat:white()}),
+        ];
+        for (axis, rect) in [2, 1, 0].iter().zip(&rects) {
+            let b = rect.bound(0., 1.);
+            assert!((b.max() - b.min()).min_comp() > 0.);
+            assert_eq!((b.min()[*axis], b.max()[*axis]), (2. - RECT_PAD, 2. + RECT_PAD));
+            // A ray along the rect's plane still gets through the box.
+            let ori = with(Vec3d::new(-1., -1., -1.), *axis, 2.);
+            let dir = with(Vec3d::new(1., 1., 1.), *axis, 0.);
+            assert!(b.hit(&Ray::new(ori, dir, 0.), 0., f64::INFINITY));
+        }
+    }
+
+    #[test]
+    fn cuboid_is_hit_from_every_side() {
+        let (p0, p1) = (Vec3d::new(0., 0., 0.), Vec3d::new(1., 2., 3.));
+        let cuboid = Cuboid::new(p1, p0, white());
+        let center = 0.5*(p0 + p1);
+        for axis in 0..3 {
+            for side in [-1., 1.] {
+                let normal = with(Vec3d::zero(), axis, side);
+                let face = with(center, axis, if side > 0. {p1[axis]} else {p0[axis]});
+                let hit = shoot(&cuboid, face, 5.*normal);
+                assert!(hit.h && hit.f, "missed the {} side of axis {}", side, axis);
+                assert!((hit.t - 1.).abs() < 1e-9);
+                assert!((hit.p - face).len() < 1e-9);
+                assert!((hit.n - normal).len() < 1e-12);
+                // From inside the same face is a back face.
+                let inside = cuboid.hit(&Ray::new(center, normal, 0.), 0.001, f64::INFINITY);
+                assert!(inside.h && !inside.f);
+                assert!((inside.p - face).len() < 1e-9);
+                assert!((inside.n + normal).len() < 1e-12);
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use crate::vector::Vec3d;
+use crate::color::Spectrum;
+use crate::sampler::Sampler;
+use crate::distribution::Distribution2d;
+use crate::environment::{EnvMap, latlong_distribution, latlong_pdf, latlong_sample, uv_to_direction};
+use crate::pdf::Onb;
+use std::f64::consts::PI;
+
+// The sun seen from the earth is about 0.53 degrees across.
+pub const SUN_ANGULAR_RADIUS: f64 = 0.004675;
+// Luminance of the sun above the atmosphere, in the model's kcd/m^2.
+const SUN_LUMINANCE: f64 = 1.6e6;
+// Brings kcd/m^2 down to about 1 for a white surface in full sun.
+const SKY_SCALE: f64 = 0.05;
+
+// Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight" (1999). The sky is
+// the model's Perez fit for luminance and chromaticity; the sun is a disk of the right size,
+// dimmed and reddened by Rayleigh and aerosol scattering along its path through the air.
+// Below the horizon is a diffuse ground of `ground_albedo` lit by the sky and the sun.
+// The model holds for the sun above the horizon, so lower elevations are clamped to it.
+pub struct PreethamSky {
+    pub turbidity: f64,
+    pub sun_elevation: f64,
+    pub sun_azimuth: f64,
+    pub ground_albedo: Spectrum,
+    sun_dir: Vec3d,
+    sun_radiance: Spectrum,
+    ground: Spectrum,
+    perez: [[f64; 5]; 3],
+    zenith: [f64; 3],
+    distribution: Distribution2d,
+    sun_weight: f64,
+}
+
+impl PreethamSky {
+    // Angles in degrees. Azimuth is measured from +x towards +z.
+    pub fn new(turbidity: f64, sun_elevation: f64, sun_azimuth: f64, ground_albedo: Spectrum) -> PreethamSky {
+        let t = turbidity.max(1.);
+        let elevation = sun_elevation.to_radians().max(0.);
+        let azimuth = sun_azimuth.to_radians();
+        let sun_dir = Vec3d::new(elevation.cos()*azimuth.cos(), elevation.sin(), elevation.cos()*azimuth.sin());
+        let theta_s = PI/2. - elevation;
+
+        let perez = [
+            [0.1787*t - 1.4630, -0.3554*t + 0.4275, -0.0227*t + 5.3251, 0.1206*t - 2.5771, -0.0670*t + 0.3703],
+            [-0.0193*t - 0.2592, -0.0665*t + 0.0008, -0.0004*t + 0.2125, -0.0641*t - 0.8989, -0.0033*t + 0.0452],
+            [-0.0167*t - 0.2608, -0.0950*t + 0.0092, -0.0079*t + 0.2102, -0.0441*t - 1.6537, -0.0109*t + 0.0529],
+        ];
+        let chi = (4./9. - t/120.)*(PI - 2.*theta_s);
+        let luminance = (4.0453*t - 4.9710)*chi.tan() - 0.2155*t + 2.4192;
+        let th = [theta_s*theta_s*theta_s, theta_s*theta_s, theta_s, 1.];
+        let poly = |c: [[f64; 4]; 3]| -> f64 {
+            let row = |r: [f64; 4]| r.iter().zip(th.iter()).map(|(a, b)| a*b).sum::<f64>();
+            t*t*row(c[0]) + t*row(c[1]) + row(c[2])
+        };
+        let x = poly([[0.00166, -0.00375, 0.00209, 0.], [-0.02903, 0.06377, -0.03202, 0.00394], [0.11693, -0.21196, 0.06052, 0.25886]]);
+        let y = poly([[0.00275, -0.00610, 0.00317, 0.], [-0.04214, 0.08970, -0.04153, 0.00516], [0.15346, -0.26756, 0.06670, 0.26688]]);
+
+        let mut sky = PreethamSky{turbidity, sun_elevation, sun_azimuth, ground_albedo, sun_dir,
+            sun_radiance:sun_radiance(t, theta_s), ground:Spectrum::black(), perez, zenith:[luminance.max(0.), x, y],
+            distribution:Distribution2d::new(&[1.], 1, 1).unwrap(), sun_weight:0.};
+
+        // Light falling on the ground: the sky by quadrature over the upper hemisphere, plus the sun.
+        let (nu, nv) = (64, 32);
+        let mut irradiance = Spectrum::black();
+        let mut sky_power = 0.;
+        for j in 0..nv {
+            for i in 0..nu {
+                let dir = uv_to_direction((i as f64 + 0.5)/nu as f64, (j as f64 + 0.5)/nv as f64);
+                let d_omega = (2.*PI/nu as f64)*(PI/nv as f64)*(((j as f64 + 0.5)/nv as f64)*PI).sin();
+                if dir.y > 0. {
+                    let l = sky.sky_radiance(dir);
+                    irradiance += l*(dir.y*d_omega);
+                    sky_power += l.luminance()*d_omega;
+                }
+            }
+        }
+        let sun_solid_angle = 2.*PI*(1. - SUN_ANGULAR_RADIUS.cos());
+        irradiance += sky.sun_radiance*(sun_solid_angle*sky.sun_dir.y);
+        sky.ground = ground_albedo*irradiance/PI;
+
+        // The ground sends light back up too, so it is part of the tabulated density.
+        sky.distribution = latlong_distribution(256, 128, |u, v| sky.radiance_without_sun(uv_to_direction(u, v)).luminance()).unwrap();
+        let ground_power = 2.*PI*sky.ground.luminance();
+        let sun_power = if sky.sun_dir.y > 0. {sky.sun_radiance.luminance()*sun_solid_angle} else {0.};
+        let total = sky_power + ground_power + sun_power;
+        sky.sun_weight = if sun_power > 0. {(sun_power/total).clamp(0.1, 0.9)} else {0.};
+        sky
+    }
+    pub fn sun_direction(&self) -> Vec3d {
+        self.sun_dir
+    }
+    fn perez(&self, k: usize, cos_theta: f64, gamma: f64) -> f64 {
+        let [a, b, c, d, e] = self.perez[k];
+        (1. + a*(b/cos_theta).exp())*(1. + c*(d*gamma).exp() + e*gamma.cos()*gamma.cos())
+    }
+    fn sky_radiance(&self, dir: Vec3d) -> Spectrum {
+        let cos_theta = dir.y.max(0.001);
+        let cos_theta_s = self.sun_dir.y;
+        let gamma = Vec3d::dot(dir, self.sun_dir).clamp(-1., 1.).acos();
+        let theta_s = cos_theta_s.clamp(-1., 1.).acos();
+        let mut xyy = [0.; 3];
+        for (k, c) in xyy.iter_mut().enumerate() {
+            *c = self.zenith[k]*self.perez(k, cos_theta, gamma)/self.perez(k, 1., theta_s);
+        }
+        xyy_to_rgb(xyy[1], xyy[2], xyy[0])*SKY_SCALE
+    }
+    fn radiance_without_sun(&self, dir: Vec3d) -> Spectrum {
+        if dir.y < 0. {self.ground} else {self.sky_radiance(dir)}
+    }
+    fn in_sun(&self, dir: Vec3d) -> bool {
+        self.sun_dir.y > 0. && Vec3d::dot(dir, self.sun_dir) >= SUN_ANGULAR_RADIUS.cos()
+    }
+}
+
+impl EnvMap for PreethamSky {
+    fn radiance(&self, dir: Vec3d) -> Spectrum {
+        let l = self.radiance_without_sun(dir);
+        if self.in_sun(dir) {l + self.sun_radiance} else {l}
+    }
+    // The sun gets a share of the samples by its power, the rest follow the tabulated sky.
+    fn sample_direction(&self, sampler: &mut dyn Sampler) -> Vec3d {
+        if sampler.get_1d() < self.sun_weight {
+            let (u1, u2) = sampler.get_2d();
+            let z = 1. + u2*(SUN_ANGULAR_RADIUS.cos() - 1.);
+            let phi = 2.*PI*u1;
+            let s = (1. - z*z).max(0.).sqrt();
+            return Onb::new(self.sun_dir).local(Vec3d::new(phi.cos()*s, phi.sin()*s, z));
+        }
+        latlong_sample(&self.distribution, sampler)
+    }
+    fn pdf(&self, dir: Vec3d) -> f64 {
+        let sun = if self.in_sun(dir) {1./(2.*PI*(1. - SUN_ANGULAR_RADIUS.cos()))} else {0.};
+        self.sun_weight*sun + (1. - self.sun_weight)*latlong_pdf(&self.distribution, dir)
+    }
+}
+
+// Transmittance of the atmosphere along the sun's path, at 680, 550 and 440 nm, with the
+// air mass from Kasten and Young and Angstrom's formula for the aerosols.
+fn sun_radiance(turbidity: f64, theta_s: f64) -> Spectrum {
+    let degrees = theta_s.to_degrees().min(93.);
+    let air_mass = 1./(theta_s.cos().max(0.) + 0.15*(93.885 - degrees).powf(-1.253));
+    let beta = 0.04608*turbidity - 0.04586;
+    let tau = |lambda: f64| {
+        let rayleigh = 0.008735*lambda.powf(-4.08);
+        let aerosol = beta*lambda.powf(-1.3);
+        (-(rayleigh + aerosol)*air_mass).exp()
+    };
+    Spectrum::new(tau(0.68), tau(0.55), tau(0.44))*(SUN_LUMINANCE*SKY_SCALE)
+}
+
+// CIE xyY to linear sRGB.
+fn xyy_to_rgb(x: f64, y: f64, lum: f64) -> Spectrum {
+    if y <= 0. {
+        return Spectrum::black();
+    }
+    let cx = x/y*lum;
+    let cz = (1. - x - y)/y*lum;
+    let r = 3.2406*cx - 1.5372*lum - 0.4986*cz;
+    let g = -0.9689*cx + 1.8758*lum + 0.0415*cz;
+    let b = 0.0557*cx - 0.2040*lum + 1.0570*cz;
+    Spectrum::new(r.max(0.), g.max(0.), b.max(0.))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    fn sky() -> PreethamSky {
+        PreethamSky::new(3., 30., 40., Spectrum::new(0.3, 0.3, 0.3))
+    }
+
+    // The direction `angle` radians away from the sun, towards `phi` around it.
+    fn off_sun(sky: &PreethamSky, angle: f64, phi: f64) -> Vec3d {
+        Onb::new(sky.sun_dir).local(Vec3d::new(angle.sin()*phi.cos(), angle.sin()*phi.sin(), angle.cos()))
+    }
+
+    #[test]
+    fn pdf_matches_the_samples() {
+        let sky = sky();
+        let mut sampler = IndependentSampler::new(1);
+        let n = 200000;
+        let (mut solid_angle, mut cosine, mut in_sun) = (0., 0., 0);
+        for _ in 0..n {
+            let dir = sky.sample_direction(&mut sampler);
+            assert!((dir.len() - 1.).abs() < 1e-9);
+            let pdf = sky.pdf(dir);
+            assert!(pdf > 0., "sampled {:?} with no density", dir);
+            // Monte Carlo estimates of the whole sphere and of the cosine over the upper half.
+            solid_angle += 1./pdf;
+            cosine += dir.y.max(0.)/pdf;
+            in_sun += sky.in_sun(dir) as usize;
+        }
+        let (solid_angle, cosine) = (solid_angle/n as f64, cosine/n as f64);
+        assert!((solid_angle/(4.*PI) - 1.).abs() < 0.03, "sphere comes out as {}", solid_angle);
+        assert!((cosine/PI - 1.).abs() < 0.03, "cosine integral comes out as {}", cosine);
+        // The tabulated sky hardly ever lands in the sun, so its share is the sun's weight.
+        let share = in_sun as f64/n as f64;
+        assert!(sky.sun_weight > 0. && (share - sky.sun_weight).abs() < 0.01, "{} of the samples in the sun", share);
+    }
+
+    #[test]
+    fn sun_disk_has_its_angular_radius() {
+        let sky = sky();
+        assert!((2.*SUN_ANGULAR_RADIUS.to_degrees() - 0.53).abs() < 0.01);
+        for k in 0..8 {
+            let phi = k as f64*PI/4.;
+            assert!(sky.in_sun(off_sun(&sky, 0.99*SUN_ANGULAR_RADIUS, phi)));
+            assert!(!sky.in_sun(off_sun(&sky, 1.01*SUN_ANGULAR_RADIUS, phi)));
+        }
+        // The sun's part of the density integrates to its weight over the disk, so the solid
+        // angle it divides by is the disk's.
+        let (nt, np) = (200, 64);
+        let dt = SUN_ANGULAR_RADIUS/nt as f64;
+        let dp = 2.*PI/np as f64;
+        let mut total = 0.;
+        let mut area = 0.;
+        for i in 0..nt {
+            let theta = (i as f64 + 0.5)*dt;
+            for j in 0..np {
+                let d_omega = theta.sin()*dt*dp;
+                total += sky.pdf(off_sun(&sky, theta, j as f64*dp))*d_omega;
+                area += d_omega;
+            }
+        }
+        assert!((area/(2.*PI*(1. - SUN_ANGULAR_RADIUS.cos())) - 1.).abs() < 1e-3);
+        assert!((total/sky.sun_weight - 1.).abs() < 1e-3, "sun density integrates to {}", total);
+    }
+
+    #[test]
+    fn sky_radiance_is_finite_and_non_negative() {
+        for turbidity in [1.5, 3., 6., 10.] {
+            for elevation in [0., 2., 15., 45., 89.] {
+                let sky = PreethamSky::new(turbidity, elevation, 120., Spectrum::new(0.2, 0.2, 0.2));
+                for i in 0..32 {
+                    for j in 0..16 {
+                        let dir = uv_to_direction((i as f64 + 0.5)/32., 0.5 + (j as f64 + 0.5)/32.);
+                        assert!(dir.y > 0.);
+                        let l = sky.radiance(dir);
+                        for c in [l.r, l.g, l.b] {
+                            assert!(c.is_finite() && c >= 0., "{} at turbidity {}, elevation {}", c, turbidity, elevation);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
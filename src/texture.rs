@@ -1,16 +1,18 @@
-use crate::color::RGB;
+use crate::color::{RGB, Spectrum};
 use crate::vector::Vec3d;
+use crate::tonemap::Encoding;
+use crate::perlin::Perlin;
 
-pub trait Texture{
-    fn value(&self, u: f64, v: f64, p: Vec3d) -> RGB;
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f64, v: f64, p: Vec3d) -> Spectrum;
 }
 
 pub struct SolidTexture {
-    pub color: RGB,
+    pub color: Spectrum,
 }
 
 impl Texture for SolidTexture {
-    fn value(&self, _u: f64, _v: f64, _p: Vec3d) -> RGB {
+    fn value(&self, _u: f64, _v: f64, _p: Vec3d) -> Spectrum {
         self.color
     }
 }
@@ -21,7 +23,8 @@ pub struct CheckerTexture {
 }
 
 impl Texture for CheckerTexture {
-    fn value(&self, u: f64, v: f64, p: Vec3d) -> RGB {
+    #[allow(clippy::needless_return)]
+    fn value(&self, u: f64, v: f64, p: Vec3d) -> Spectrum {
         let sines = (10.*p.x).sin()*(10.*p.y).sin()*(10.*p.z).sin();
         if sines < 0. {
             return self.odd.value(u, v, p);
@@ -33,7 +36,7 @@ impl Texture for CheckerTexture {
 }
 
 impl CheckerTexture {
-    pub fn new(odd: RGB, even: RGB) -> CheckerTexture {
+    pub fn new(odd: Spectrum, even: Spectrum) -> CheckerTexture {
         let odd = Box::new(SolidTexture{color: odd});
         let even = Box::new(SolidTexture{color: even});
         CheckerTexture{odd, even}
@@ -48,19 +51,21 @@ pub struct ImageTexture {
 
 impl ImageTexture {
     pub fn new(filename: &str) -> ImageTexture {
-        let path = std::path::Path::new(&filename);
-        let data = bmp::open(path).unwrap_or_else(|e| {
+        ImageTexture::open(filename).unwrap_or_else(|e| {
             panic!("Failed to open: {}", e);
-        });
+        })
+    }
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> bmp::BmpResult<ImageTexture> {
+        let data = bmp::open(path)?;
         let width = data.get_width();
         let height = data.get_height();
 
-        ImageTexture{data, width, height}
+        Ok(ImageTexture{data, width, height})
     }
 }
 
 impl Texture for ImageTexture {
-    fn value(&self, u: f64, v: f64, _p: Vec3d) -> RGB {
+    fn value(&self, u: f64, v: f64, _p: Vec3d) -> Spectrum {
         let u = clamp(u, 0., 1.);
         let v = 1. - clamp(v, 0., 1.);
         let mut i = (u*self.width as f64) as u32;
@@ -69,10 +74,90 @@ impl Texture for ImageTexture {
         if j >= self.height{j = self.height - 1}
         let pixel = self.data.get_pixel(i, j);
 
-        RGB::from_pixel(pixel)
+        // Images are stored sRGB encoded, shading needs linear values.
+        let c = RGB::from_pixel(pixel);
+        Spectrum::new(Encoding::Srgb.decode(c.r), Encoding::Srgb.decode(c.g), Encoding::Srgb.decode(c.b))
     }
 }
 
+// Piecewise linear map from [0, 1] to colors. Stops are kept sorted by position, and stops
+// without one (NaN) are dropped.
+#[derive(Clone, Debug)]
+pub struct ColorRamp {
+    pub stops: Vec<(f64, Spectrum)>,
+}
+
+impl ColorRamp {
+    pub fn new(mut stops: Vec<(f64, Spectrum)>) -> ColorRamp {
+        stops.retain(|s| !s.0.is_nan());
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        ColorRamp{stops}
+    }
+    pub fn linear(c0: Spectrum, c1: Spectrum) -> ColorRamp {
+        ColorRamp::new(vec![(0., c0), (1., c1)])
+    }
+    pub fn at(&self, t: f64) -> Spectrum {
+        if self.stops.is_empty() {
+            return Spectrum::black();
+        }
+        let first = self.stops[0];
+        if t <= first.0 {
+            return first.1;
+        }
+        for w in self.stops.windows(2) {
+            let ((t0, c0), (t1, c1)) = (w[0], w[1]);
+            if t <= t1 {
+                let f = if t1 > t0 {(t - t0)/(t1 - t0)} else {1.};
+                return (1. - f)*c0 + f*c1;
+            }
+        }
+        self.stops[self.stops.len() - 1].1
+    }
+}
+
+pub struct NoiseTexture {
+    pub noise: Perlin,
+    pub scale: f64,
+    pub octaves: u32,
+    pub ramp: ColorRamp,
+}
+
+impl NoiseTexture {
+    pub fn new(noise: Perlin, scale: f64) -> NoiseTexture {
+        NoiseTexture{noise, scale, octaves:1, ramp:ColorRamp::linear(Spectrum::black(), Spectrum::white())}
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f64, _v: f64, p: Vec3d) -> Spectrum {
+        let n = self.noise.fbm(self.scale*p, self.octaves);
+        self.ramp.at(0.5*(1. + n))
+    }
+}
+
+// Veins from a sine wave along z, phase shifted by turbulence.
+pub struct MarbleTexture {
+    pub noise: Perlin,
+    pub scale: f64,
+    pub octaves: u32,
+    pub turbulence: f64,
+    pub ramp: ColorRamp,
+}
+
+impl MarbleTexture {
+    pub fn new(noise: Perlin, scale: f64) -> MarbleTexture {
+        MarbleTexture{noise, scale, octaves:7, turbulence:10., ramp:ColorRamp::linear(Spectrum::black(), Spectrum::white())}
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, _u: f64, _v: f64, p: Vec3d) -> Spectrum {
+        let phase = self.scale*p.z + self.turbulence*self.noise.turb(p, self.octaves);
+        self.ramp.at(0.5*(1. + phase.sin()))
+    }
+}
+
+#[allow(clippy::needless_return)]
 fn clamp(v: f64, down: f64, up: f64) -> f64
 {
     if v > up
@@ -88,3 +173,38 @@ fn clamp(v: f64, down: f64, up: f64) -> f64
         return v;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn ramp_sorts_stops_and_drops_nan() {
+        let ramp = ColorRamp::new(vec![
+            (1., Spectrum::white()),
+            (f64::NAN, Spectrum::new(1., 0., 0.)),
+            (0., Spectrum::black())]);
+        assert_eq!(ramp.stops.len(), 2);
+        assert_eq!(ramp.at(0.25), Spectrum::new(0.25, 0.25, 0.25));
+        assert_eq!(ramp.at(2.), Spectrum::white());
+    }
+
+    #[test]
+    fn noise_textures_follow_octaves_and_scale() {
+        let perlin = || Perlin::new(&mut IndependentSampler::new(3));
+        let p = Vec3d::new(1.3, 0.7, -2.2);
+        let base = NoiseTexture::new(perlin(), 2.);
+        let v = base.value(0., 0., p);
+        assert_eq!(v, NoiseTexture::new(perlin(), 2.).value(0., 0., p));
+        assert!(v.r >= 0. && v.r <= 1.);
+        assert_ne!(v, NoiseTexture::new(perlin(), 5.).value(0., 0., p));
+        assert_ne!(v, NoiseTexture{octaves:5, ..NoiseTexture::new(perlin(), 2.)}.value(0., 0., p));
+        let marble = MarbleTexture::new(perlin(), 4.);
+        let m = marble.value(0., 0., p);
+        assert!(m.r >= 0. && m.r <= 1.);
+        assert_ne!(m, MarbleTexture::new(perlin(), 1.).value(0., 0., p));
+        assert_ne!(m, MarbleTexture{octaves:1, ..MarbleTexture::new(perlin(), 4.)}.value(0., 0., p));
+        assert_ne!(m, MarbleTexture{turbulence:0., ..MarbleTexture::new(perlin(), 4.)}.value(0., 0., p));
+    }
+}
@@ -0,0 +1,169 @@
+use crate::color::{RGB, Spectrum};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMap {
+    Clamp,
+    Reinhard,
+    // `white` is the smallest value that maps to 1, here and for Hable.
+    ExtendedReinhard{white: f64},
+    Aces,
+    Hable{white: f64},
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Linear,
+    Gamma(f64),
+    Srgb,
+}
+
+// The steps between the accumulated linear radiance and the 8-bit pixel.
+#[derive(Clone, Copy, Debug)]
+pub struct PostProcess {
+    pub exposure: f64,
+    pub tonemap: ToneMap,
+    pub encoding: Encoding,
+}
+
+impl PostProcess {
+    pub fn new(exposure: f64, tonemap: ToneMap, encoding: Encoding) -> PostProcess {
+        PostProcess{exposure, tonemap, encoding}
+    }
+    pub fn apply(&self, c: Spectrum) -> RGB {
+        // Exposure is given in stops.
+        let c = c*2f64.powf(self.exposure);
+        let c = self.tonemap.map(c);
+        RGB::new(self.encoding.encode(c.r), self.encoding.encode(c.g), self.encoding.encode(c.b))
+    }
+}
+
+impl Default for PostProcess {
+    fn default() -> PostProcess {
+        PostProcess::new(0., ToneMap::Clamp, Encoding::Srgb)
+    }
+}
+
+impl ToneMap {
+    pub fn map(&self, c: Spectrum) -> Spectrum {
+        match *self {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => per_channel(c, |x| x/(1. + x)),
+            ToneMap::ExtendedReinhard{white} => per_channel(c, |x| x*(1. + x/(white*white))/(1. + x)),
+            ToneMap::Aces => per_channel(c, aces),
+            ToneMap::Hable{white} => {
+                // The 2x exposure bias is the one from the Uncharted 2 presentation.
+                let scale = 1./hable(2.*white);
+                per_channel(c, |x| hable(2.*x)*scale)
+            }
+        }
+    }
+}
+
+impl Encoding {
+    pub fn encode(&self, x: f64) -> f64 {
+        let x = x.clamp(0., 1.);
+        match *self {
+            Encoding::Linear => x,
+            Encoding::Gamma(g) => x.powf(1./g),
+            Encoding::Srgb => {
+                if x <= 0.0031308 {
+                    12.92*x
+                }
+                else {
+                    1.055*x.powf(1./2.4) - 0.055
+                }
+            }
+        }
+    }
+    pub fn decode(&self, x: f64) -> f64 {
+        match *self {
+            Encoding::Linear => x,
+            Encoding::Gamma(g) => x.powf(g),
+            Encoding::Srgb => {
+                if x <= 0.04045 {
+                    x/12.92
+                }
+                else {
+                    ((x + 0.055)/1.055).powf(2.4)
+                }
+            }
+        }
+    }
+}
+
+fn per_channel<F: Fn(f64) -> f64>(c: Spectrum, f: F) -> Spectrum {
+    Spectrum::new(f(c.r.max(0.)), f(c.g.max(0.)), f(c.b.max(0.)))
+}
+
+// Narkowicz's curve fit of the ACES reference rendering transform.
+fn aces(x: f64) -> f64 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    x*(a*x + b)/(x*(c*x + d) + e)
+}
+
+fn hable(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x*(a*x + c*b) + d*e)/(x*(a*x + b) + d*f) - e/f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for k in 0..=100 {
+            let x = k as f64/100.;
+            assert!(close(Encoding::Srgb.encode(Encoding::Srgb.decode(x)), x), "{}", x);
+            assert!(close(Encoding::Srgb.decode(Encoding::Srgb.encode(x)), x), "{}", x);
+        }
+        // The linear segment near black.
+        assert!(close(Encoding::Srgb.encode(0.002), 12.92*0.002));
+        assert!(close(Encoding::Srgb.decode(0.02), 0.02/12.92));
+        // And the curve takes over where the segment ends.
+        let (a, b) = (0.0031308, 0.04045);
+        assert!((Encoding::Srgb.encode(a) - b).abs() < 1e-5);
+        assert!((1.055*a.powf(1./2.4) - 0.055 - b).abs() < 1e-5);
+        assert!(close(Encoding::Srgb.encode(1.5), 1.));
+        assert_eq!(Encoding::Srgb.encode(-0.5), 0.);
+        assert!(close(Encoding::Gamma(2.2).decode(Encoding::Gamma(2.2).encode(0.3)), 0.3));
+    }
+
+    #[test]
+    fn exposure_is_in_stops() {
+        let grey = Spectrum::new(0.25, 0.25, 0.25);
+        let up = PostProcess::new(1., ToneMap::Clamp, Encoding::Linear).apply(grey);
+        let down = PostProcess::new(-2., ToneMap::Clamp, Encoding::Linear).apply(grey);
+        assert!(close(up.r, 0.5) && close(up.g, 0.5) && close(up.b, 0.5));
+        assert!(close(down.r, 0.0625));
+    }
+
+    fn map(t: ToneMap, x: f64) -> f64 {
+        t.map(Spectrum::new(x, x, x)).r
+    }
+
+    #[test]
+    fn operators_give_their_curves() {
+        assert_eq!(map(ToneMap::Clamp, 3.), 3.);
+        assert!(close(map(ToneMap::Reinhard, 1.), 0.5));
+        assert!(close(map(ToneMap::Reinhard, 3.), 0.75));
+        let extended = ToneMap::ExtendedReinhard{white:4.};
+        assert!(close(map(extended, 4.), 1.));
+        assert!(close(map(extended, 1.), 1.0625/2.));
+        assert_eq!(map(ToneMap::Aces, 0.), 0.);
+        assert!(close(map(ToneMap::Aces, 1.), 2.54/3.16));
+        assert!(close(map(ToneMap::Aces, 0.18), 0.18*(2.51*0.18 + 0.03)/(0.18*(2.43*0.18 + 0.59) + 0.14)));
+        let hable = ToneMap::Hable{white:11.2};
+        assert!(close(map(hable, 11.2), 1.));
+        assert!(close(map(hable, 0.), 0.));
+        assert!(map(hable, 1.) < map(hable, 2.) && map(hable, 2.) < 1.);
+        // Negative values come out black rather than as negative light.
+        for t in [ToneMap::Reinhard, extended, ToneMap::Aces, hable] {
+            assert_eq!(map(t, -1.), map(t, 0.));
+        }
+    }
+}
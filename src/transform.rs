@@ -0,0 +1,270 @@
+use crate::vector::Vec3d;
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::shape::{Hit, Shape};
+use crate::sampler::Sampler;
+use std::ops;
+use std::sync::Arc;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Mat4
+{
+    pub m: [[f64; 4]; 4],
+}
+
+impl Mat4
+{
+    pub fn identity() -> Mat4
+    {
+        let mut m = [[0.; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            row[i] = 1.;
+        }
+        Mat4{m}
+    }
+    pub fn translate(d: Vec3d) -> Mat4
+    {
+        let mut t = Mat4::identity();
+        t.m[0][3] = d.x;
+        t.m[1][3] = d.y;
+        t.m[2][3] = d.z;
+        t
+    }
+    pub fn scale(s: Vec3d) -> Mat4
+    {
+        let mut t = Mat4::identity();
+        t.m[0][0] = s.x;
+        t.m[1][1] = s.y;
+        t.m[2][2] = s.z;
+        t
+    }
+    // Rotation by `degrees` around `axis`, counter-clockwise when looking down the axis.
+    pub fn rotate(axis: Vec3d, degrees: f64) -> Mat4
+    {
+        let a = axis.norm();
+        let (s, c) = degrees.to_radians().sin_cos();
+        let mut t = Mat4::identity();
+        t.m[0][0] = a.x*a.x + (1. - a.x*a.x)*c;
+        t.m[0][1] = a.x*a.y*(1. - c) - a.z*s;
+        t.m[0][2] = a.x*a.z*(1. - c) + a.y*s;
+        t.m[1][0] = a.x*a.y*(1. - c) + a.z*s;
+        t.m[1][1] = a.y*a.y + (1. - a.y*a.y)*c;
+        t.m[1][2] = a.y*a.z*(1. - c) - a.x*s;
+        t.m[2][0] = a.x*a.z*(1. - c) - a.y*s;
+        t.m[2][1] = a.y*a.z*(1. - c) + a.x*s;
+        t.m[2][2] = a.z*a.z + (1. - a.z*a.z)*c;
+        t
+    }
+    pub fn transpose(&self) -> Mat4
+    {
+        let mut t = Mat4::identity();
+        for i in 0..4 {
+            for j in 0..4 {
+                t.m[i][j] = self.m[j][i];
+            }
+        }
+        t
+    }
+    // Gauss-Jordan elimination with partial pivoting. `None` for singular matrices.
+    pub fn inverse(&self) -> Option<Mat4>
+    {
+        let mut a = self.m;
+        let mut inv = Mat4::identity().m;
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs())).unwrap();
+            if a[pivot][col].is_nan() || a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+            let d = a[col][col];
+            for j in 0..4 {
+                a[col][j] /= d;
+                inv[col][j] /= d;
+            }
+            for i in 0..4 {
+                if i == col {
+                    continue;
+                }
+                let f = a[i][col];
+                for j in 0..4 {
+                    a[i][j] -= f*a[col][j];
+                    inv[i][j] -= f*inv[col][j];
+                }
+            }
+        }
+        Some(Mat4{m:inv})
+    }
+    pub fn point(&self, p: Vec3d) -> Vec3d
+    {
+        let m = &self.m;
+        let x = m[0][0]*p.x + m[0][1]*p.y + m[0][2]*p.z + m[0][3];
+        let y = m[1][0]*p.x + m[1][1]*p.y + m[1][2]*p.z + m[1][3];
+        let z = m[2][0]*p.x + m[2][1]*p.y + m[2][2]*p.z + m[2][3];
+        let w = m[3][0]*p.x + m[3][1]*p.y + m[3][2]*p.z + m[3][3];
+        if w == 1. {Vec3d::new(x, y, z)} else {Vec3d::new(x, y, z)/w}
+    }
+    pub fn vector(&self, v: Vec3d) -> Vec3d
+    {
+        let m = &self.m;
+        let x = m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z;
+        let y = m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z;
+        let z = m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z;
+        Vec3d::new(x, y, z)
+    }
+}
+
+impl ops::Mul for Mat4
+{
+    type Output = Mat4;
+    fn mul(self, other: Mat4) -> Mat4
+    {
+        let mut m = [[0.; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = (0..4).map(|k| self.m[i][k]*other.m[k][j]).sum();
+            }
+        }
+        Mat4{m}
+    }
+}
+
+// An affine transform kept together with its inverse.
+#[derive(Copy, Clone, Debug)]
+pub struct Transform {
+    pub m: Mat4,
+    pub inv: Mat4,
+}
+
+impl Transform {
+    // `None` when `m` has no inverse, e.g. it flattens space onto a plane.
+    pub fn new(m: Mat4) -> Option<Transform> {
+        let inv = m.inverse()?;
+        Some(Transform{m, inv})
+    }
+    pub fn identity() -> Transform {
+        Transform{m:Mat4::identity(), inv:Mat4::identity()}
+    }
+    pub fn translate(d: Vec3d) -> Transform {
+        Transform{m:Mat4::translate(d), inv:Mat4::translate(-d)}
+    }
+    // `None` unless every factor is finite and nonzero.
+    pub fn scale(s: Vec3d) -> Option<Transform> {
+        if [s.x, s.y, s.z].iter().any(|f| *f == 0. || !f.is_finite()) {
+            return None;
+        }
+        Some(Transform{m:Mat4::scale(s), inv:Mat4::scale(Vec3d::new(1./s.x, 1./s.y, 1./s.z))})
+    }
+    pub fn rotate(axis: Vec3d, degrees: f64) -> Transform {
+        let m = Mat4::rotate(axis, degrees);
+        Transform{m, inv:m.transpose()}
+    }
+    pub fn inverse(&self) -> Transform {
+        Transform{m:self.inv, inv:self.m}
+    }
+    // `a.then(b)` applies `a` first and `b` second.
+    pub fn then(&self, next: Transform) -> Transform {
+        Transform{m:next.m*self.m, inv:self.inv*next.inv}
+    }
+    pub fn point(&self, p: Vec3d) -> Vec3d {
+        self.m.point(p)
+    }
+    pub fn vector(&self, v: Vec3d) -> Vec3d {
+        self.m.vector(v)
+    }
+    // Normals go through the inverse transpose so they stay perpendicular to the surface.
+    pub fn normal(&self, n: Vec3d) -> Vec3d {
+        self.inv.transpose().vector(n)
+    }
+    pub fn bound(&self, b: &AABB) -> AABB {
+        let (lo, hi) = (b.min(), b.max());
+        let mut out = AABB::new(self.point(lo), self.point(lo));
+        for i in 1..8 {
+            let corner = Vec3d::new(
+                if i & 1 == 0 {lo.x} else {hi.x},
+                if i & 2 == 0 {lo.y} else {hi.y},
+                if i & 4 == 0 {lo.z} else {hi.z});
+            let p = self.point(corner);
+            out = AABB::union_box(&out, &AABB::new(p, p));
+        }
+        out
+    }
+}
+
+// Places a shared shape in the world through a transform, without copying the shape.
+#[derive(Clone)]
+pub struct Instance {
+    pub shape: Arc<dyn Shape>,
+    pub transform: Transform,
+}
+
+impl Instance {
+    pub fn new(shape: Arc<dyn Shape>, transform: Transform) -> Instance {
+        Instance{shape, transform}
+    }
+}
+
+impl Shape for Instance {
+    fn hit(&self, r: &Ray, tmin: f64, tmax: f64) -> Hit {
+        // The direction is not normalized, so `t` means the same distance in both spaces.
+        let r_obj = Ray::new(self.transform.inv.point(r.ori), self.transform.inv.vector(r.dir), r.t).with_key(r.key);
+        let mut hit = self.shape.hit(&r_obj, tmin, tmax);
+        if !hit.h {
+            return hit;
+        }
+        hit.p = self.transform.point(hit.p);
+        hit.n = self.transform.normal(hit.n).norm();
+        hit.ng = self.transform.normal(hit.ng).norm();
+        hit
+    }
+    fn bound(&self, t0: f64, t1: f64) -> AABB {
+        self.transform.bound(&self.shape.bound(t0, t1))
+    }
+    fn is_light(&self) -> bool {
+        self.shape.is_light()
+    }
+    // A linear map A stretches solid angle around a unit direction d by |det A|/|A d|^3.
+    fn pdf_value(&self, o: Vec3d, v: Vec3d) -> f64 {
+        let d = self.transform.inv.vector(v).norm();
+        let pdf = self.shape.pdf_value(self.transform.inv.point(o), d);
+        if pdf <= 0. {
+            return 0.;
+        }
+        let m = &self.transform.m.m;
+        let det = m[0][0]*(m[1][1]*m[2][2] - m[1][2]*m[2][1])
+                - m[0][1]*(m[1][0]*m[2][2] - m[1][2]*m[2][0])
+                + m[0][2]*(m[1][0]*m[2][1] - m[1][1]*m[2][0]);
+        pdf*self.transform.vector(d).len().powi(3)/det.abs()
+    }
+    fn random(&self, o: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        self.transform.vector(self.shape.random(self.transform.inv.point(o), sampler))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Mat4, b: &Mat4) -> bool {
+        (0..4).all(|i| (0..4).all(|j| (a.m[i][j] - b.m[i][j]).abs() < 1e-9))
+    }
+
+    #[test]
+    fn singular_matrices_have_no_transform() {
+        assert!(Transform::new(Mat4::scale(Vec3d::new(1., 0., 1.))).is_none());
+        let mut nan = Mat4::identity();
+        nan.m[1][1] = f64::NAN;
+        assert!(Transform::new(nan).is_none());
+        assert!(Transform::scale(Vec3d::new(2., 0., 1.)).is_none());
+        assert!(Transform::scale(Vec3d::new(2., f64::INFINITY, 1.)).is_none());
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let m = Transform::rotate(Vec3d::new(1., 2., 3.), 40.).then(Transform::scale(Vec3d::new(2., -1., 0.5)).unwrap())
+            .then(Transform::translate(Vec3d::new(3., 4., 5.)));
+        assert!(close(&(m.m*m.inv), &Mat4::identity()));
+        let t = Transform::new(m.m).unwrap();
+        assert!(close(&t.inv, &m.inv));
+    }
+}
@@ -1,7 +1,6 @@
 use std::ops;
 use crate::color::RGB;
-extern crate rand;
-use rand::Rng;
+use crate::sampler::Sampler;
 
 #[derive(Copy,Clone,Debug)]
 pub struct Vec3d
@@ -63,6 +62,7 @@ impl Vec3d
     {
         f64::max(self.x, f64::max(self.y, self.z))
     }
+    #[allow(clippy::needless_return)]
     pub fn max_dim(self) -> usize
     {
         if self.x > self.y && self.x > self.z
@@ -125,22 +125,23 @@ impl Vec3d
         let b = clamp(self.z, 0., 1.);
         RGB{r, g, b}
     }
-    pub fn rand_vec(min: f64, max: f64) -> Vec3d {
-        let x = rand::thread_rng().gen_range(min, max);
-        let y = rand::thread_rng().gen_range(min, max);
-        let z = rand::thread_rng().gen_range(min, max);
+    pub fn rand_vec(min: f64, max: f64, sampler: &mut dyn Sampler) -> Vec3d {
+        let x = sampler.gen_range(min, max);
+        let y = sampler.gen_range(min, max);
+        let z = sampler.gen_range(min, max);
         Vec3d{x, y, z}
     }
-    pub fn rand_in_unit_sphere() -> Vec3d {
+    pub fn rand_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3d {
         loop {
-            let p = Vec3d::rand_vec(-1., 1.);
+            let p = Vec3d::rand_vec(-1., 1., sampler);
             if p.len() < 1. {
                 return p;
             }
         }
     }
-    pub fn rand_in_unit_hemisphere(n: Vec3d) -> Vec3d {
-        let v = Vec3d::rand_in_unit_sphere();
+    #[allow(clippy::needless_return)]
+    pub fn rand_in_unit_hemisphere(n: Vec3d, sampler: &mut dyn Sampler) -> Vec3d {
+        let v = Vec3d::rand_in_unit_sphere(sampler);
         if Vec3d::dot(v, n) > 0. {
             return v;
         }
@@ -148,13 +149,20 @@ impl Vec3d
             return -v;
         }
     }
-    pub fn rand_in_unit_disk() -> Vec3d {
-        loop {
-            let p = Vec3d::new(rand::thread_rng().gen_range(-1., 1.), rand::thread_rng().gen_range(-1., 1.), 0.);
-            if p.len() < 1. {
-                return p;
-            }
+    // Shirley-Chiu concentric mapping, so the disk always takes exactly two sampler dimensions.
+    pub fn rand_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3d {
+        let (u1, u2) = sampler.get_2d();
+        let (a, b) = (2.*u1 - 1., 2.*u2 - 1.);
+        if a == 0. && b == 0. {
+            return Vec3d::zero();
+        }
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, std::f64::consts::FRAC_PI_4*(b/a))
         }
+        else {
+            (b, std::f64::consts::FRAC_PI_2 - std::f64::consts::FRAC_PI_4*(a/b))
+        };
+        Vec3d::new(r*theta.cos(), r*theta.sin(), 0.)
     }
 }
 
@@ -235,6 +243,7 @@ impl ops::Index<usize> for Vec3d
     }
 }
 
+#[allow(clippy::needless_return)]
 fn clamp(v: f64, down: f64, up: f64) -> f64
 {
     if v > up